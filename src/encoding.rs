@@ -0,0 +1,89 @@
+//! レガシーな文字コードのファイル名の変換
+
+use crate::SourceEncoding;
+use encoding_rs::{EUC_JP, SHIFT_JIS, WINDOWS_1252};
+use std::borrow::Cow;
+use std::ffi::{OsStr, OsString};
+use std::fmt;
+
+impl SourceEncoding {
+    /// UTF-8 でない名前をこの文字コードとして解釈し、UTF-8 の名前に変換します。
+    ///
+    /// 既に UTF-8 として正しい名前はそのまま返します。
+    ///
+    /// # Returns
+    ///
+    /// 変換した名前。この文字コードとして解釈できないバイトを含む場合や、
+    /// 変換すると制御文字になるバイトを含む場合（別の文字コードの名前とみなす）は `None`
+    pub fn decode<'a>(&self, name: &'a OsStr) -> Option<Cow<'a, OsStr>> {
+        if name.to_str().is_some() {
+            return Some(Cow::Borrowed(name));
+        }
+        let bytes = name.as_encoded_bytes();
+        let text = match self {
+            // Shift_JIS は WHATWG の定義（CP932 と同じ Windows の拡張を含む）で解釈する
+            SourceEncoding::ShiftJis | SourceEncoding::Cp932 => SHIFT_JIS
+                .decode_without_bom_handling_and_without_replacement(bytes)?
+                .into_owned(),
+            SourceEncoding::EucJp => EUC_JP
+                .decode_without_bom_handling_and_without_replacement(bytes)?
+                .into_owned(),
+            SourceEncoding::Latin1 => WINDOWS_1252
+                .decode_without_bom_handling_and_without_replacement(bytes)?
+                .into_owned(),
+        };
+        if text.chars().any(char::is_control) {
+            return None;
+        }
+        Some(Cow::Owned(OsString::from(text)))
+    }
+}
+
+impl fmt::Display for SourceEncoding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            SourceEncoding::ShiftJis => "Shift_JIS",
+            SourceEncoding::EucJp => "EUC-JP",
+            SourceEncoding::Cp932 => "CP932",
+            SourceEncoding::Latin1 => "Windows-1252",
+        })
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::unix::ffi::OsStrExt;
+
+    #[test]
+    fn test_decode() {
+        let decode = |encoding: SourceEncoding, bytes: &[u8]| {
+            encoding
+                .decode(OsStr::from_bytes(bytes))
+                .map(|name| name.to_str().unwrap().to_string())
+        };
+        assert_eq!(
+            decode(SourceEncoding::ShiftJis, b"\x83e\x83X\x83g.txt").as_deref(),
+            Some("テスト.txt")
+        );
+        assert_eq!(
+            decode(SourceEncoding::EucJp, b"\xa5\xc6\xa5\xb9\xa5\xc8.txt").as_deref(),
+            Some("テスト.txt")
+        );
+        assert_eq!(
+            decode(SourceEncoding::Latin1, b"caf\xe9.txt").as_deref(),
+            Some("café.txt")
+        );
+        assert_eq!(
+            decode(SourceEncoding::Latin1, "テスト.txt".as_bytes()).as_deref(),
+            Some("テスト.txt")
+        );
+        assert_eq!(
+            decode(SourceEncoding::Latin1, b"\x93quote\x94.txt").as_deref(),
+            Some("\u{201c}quote\u{201d}.txt")
+        );
+        assert_eq!(decode(SourceEncoding::ShiftJis, b"\x83.txt"), None);
+        assert_eq!(decode(SourceEncoding::Latin1, b"a\x81b.txt"), None);
+        assert_eq!(decode(SourceEncoding::Latin1, b"a\x01\xe9.txt"), None);
+    }
+}
@@ -0,0 +1,287 @@
+//! リネーム履歴（ジャーナル）の読み書きと取り消し
+
+use crate::os_name::escape_path;
+use log::{debug, info};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// 対象ディレクトリ内に作成するジャーナルファイル名
+pub const JOURNAL_FILE_NAME: &str = ".prefix_journal.jsonl";
+
+/// 1回のリネーム実行の記録
+#[derive(Debug, Serialize, Deserialize)]
+pub struct JournalRun {
+    /// 実行日時（UNIX 時間、秒）
+    pub timestamp: u64,
+    /// 使用した正規表現パターン
+    pub pattern: String,
+    /// 使用した区切り文字
+    #[serde(default = "default_separator")]
+    pub separator: String,
+    /// 対象パス
+    #[serde(with = "crate::os_name")]
+    pub path: PathBuf,
+    /// リネームしたエントリ（実行順）
+    pub entries: Vec<JournalEntry>,
+}
+
+/// 1ファイル分のリネーム記録
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct JournalEntry {
+    /// リネーム前の名前（対象パスからの相対パス）
+    #[serde(with = "crate::os_name")]
+    pub old: PathBuf,
+    /// リネーム後の名前（対象パスからの相対パス）
+    #[serde(with = "crate::os_name")]
+    pub new: PathBuf,
+    /// リネーム後のファイルサイズ
+    pub size: u64,
+    /// リネーム後の更新日時（UNIX 時間、ナノ秒）
+    pub modified: u64,
+}
+
+/// 区切り文字が記録されていない古いジャーナルの区切り文字
+fn default_separator() -> String {
+    "_".to_string()
+}
+
+impl JournalRun {
+    /// 現在時刻で空の実行記録を作成します。
+    pub fn new(pattern: &str, separator: &str, path: &Path) -> Self {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        JournalRun {
+            timestamp,
+            pattern: pattern.to_string(),
+            separator: separator.to_string(),
+            path: path.to_path_buf(),
+            entries: Vec::new(),
+        }
+    }
+
+    /// リネーム済みのファイルを記録します。
+    ///
+    /// # Errors
+    ///
+    /// リネーム後のファイルのメタデータを取得できなかった場合
+    pub fn record(&mut self, dir: &Path, old: &Path, new: &Path) -> io::Result<()> {
+        let (size, modified) = fingerprint(&dir.join(new))?;
+        self.entries.push(JournalEntry {
+            old: old.to_path_buf(),
+            new: new.to_path_buf(),
+            size,
+            modified,
+        });
+        Ok(())
+    }
+}
+
+/// ファイルの変更検出に使うサイズと更新日時を取得します。
+///
+/// シンボリックリンクはリンク先ではなくリンク自体の情報を返します。
+/// ディレクトリは中身をリネームするだけで更新日時が変わるため、常に `(0, 0)` を返します。
+///
+/// # Errors
+///
+/// メタデータを取得できなかった場合
+pub fn fingerprint(path: &Path) -> io::Result<(u64, u64)> {
+    let metadata = fs::symlink_metadata(path)?;
+    if metadata.is_dir() {
+        return Ok((0, 0));
+    }
+    let modified = metadata
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_nanos() as u64);
+    Ok((metadata.len(), modified))
+}
+
+/// ジャーナルに実行記録を追記します。
+///
+/// # Errors
+///
+/// ジャーナルファイルの書き込みに失敗した場合
+pub fn append(dir: &Path, run: &JournalRun) -> io::Result<()> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(dir.join(JOURNAL_FILE_NAME))?;
+    writeln!(file, "{}", serde_json::to_string(run)?)?;
+    Ok(())
+}
+
+/// ジャーナルからすべての実行記録を読み込みます。
+///
+/// ジャーナルファイルが存在しない場合は空のリストを返します。
+///
+/// # Errors
+///
+/// ジャーナルファイルの読み込みまたは解析に失敗した場合
+pub fn load(dir: &Path) -> io::Result<Vec<JournalRun>> {
+    let file = match fs::File::open(dir.join(JOURNAL_FILE_NAME)) {
+        Ok(file) => file,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err),
+    };
+    let mut runs = Vec::new();
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        runs.push(serde_json::from_str(&line)?);
+    }
+    Ok(runs)
+}
+
+/// ジャーナルを指定された実行記録で置き換えます。
+///
+/// 記録が空の場合はジャーナルファイルを削除します。
+///
+/// # Errors
+///
+/// ジャーナルファイルの書き込みまたは削除に失敗した場合
+pub fn save(dir: &Path, runs: &[JournalRun]) -> io::Result<()> {
+    let path = dir.join(JOURNAL_FILE_NAME);
+    if runs.is_empty() {
+        return match fs::remove_file(path) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+            _ => Ok(()),
+        };
+    }
+    let mut file = fs::File::create(path)?;
+    for run in runs {
+        writeln!(file, "{}", serde_json::to_string(run)?)?;
+    }
+    Ok(())
+}
+
+/// 1ファイル分の取り消しの結果
+#[derive(Debug)]
+pub enum UndoStatus {
+    /// 元の名前に戻した（ドライランでは戻す予定）
+    Reverted,
+    /// リネーム後に変更されていたため残した
+    Changed,
+    /// 元の名前が既に使われていたため残した
+    Occupied,
+    /// リネームに失敗した
+    Failed(io::Error),
+}
+
+/// 取り消しの結果（処理順）
+#[derive(Debug, Default)]
+pub struct UndoReport {
+    /// 各エントリと取り消しの結果
+    pub results: Vec<(JournalEntry, UndoStatus)>,
+}
+
+impl UndoReport {
+    /// 元に戻したファイル数を返します。
+    pub fn reverted(&self) -> usize {
+        self.count(|status| matches!(status, UndoStatus::Reverted))
+    }
+
+    /// 変更が検出された、または元の名前が使われていたため残したファイル数を返します。
+    pub fn refused(&self) -> usize {
+        self.count(|status| matches!(status, UndoStatus::Changed | UndoStatus::Occupied))
+    }
+
+    fn count(&self, f: impl Fn(&UndoStatus) -> bool) -> usize {
+        self.results.iter().filter(|(_, status)| f(status)).count()
+    }
+}
+
+/// ジャーナルの最後の実行記録を逆順に適用し、リネームを取り消します。
+///
+/// ジャーナル作成後に変更されたファイルや、元の名前が既に使われているファイルには
+/// 手を付けずにジャーナルへ残します。リネームに失敗した場合はそこで中断し、
+/// 未処理のエントリもジャーナルへ残します。
+///
+/// # Arguments
+///
+/// * `dir` - 対象パス
+/// * `dry_run` - ドライランフラグ
+///
+/// # Returns
+///
+/// 各エントリの取り消しの結果
+///
+/// # Errors
+///
+/// 取り消す記録がない場合、またはジャーナルの読み書きに失敗した場合
+pub fn undo(dir: &Path, dry_run: bool) -> io::Result<UndoReport> {
+    let mut runs = load(dir)?;
+    let Some(run) = runs.pop() else {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "No journal entries to undo",
+        ));
+    };
+
+    debug!(
+        "undoing {} renames recorded at {}",
+        run.entries.len(),
+        run.timestamp
+    );
+    let mut report = UndoReport::default();
+    let mut pending = run.entries;
+    let mut kept = Vec::new();
+    while let Some(entry) = pending.pop() {
+        let new_path = dir.join(&entry.new);
+        let old_path = dir.join(&entry.old);
+        let unchanged = matches!(
+            fingerprint(&new_path),
+            Ok(fp) if fp == (entry.size, entry.modified)
+        );
+        let status = if !unchanged {
+            UndoStatus::Changed
+        } else if fs::symlink_metadata(&old_path).is_ok() {
+            UndoStatus::Occupied
+        } else if dry_run {
+            UndoStatus::Reverted
+        } else {
+            match fs::rename(&new_path, &old_path) {
+                Ok(()) => {
+                    info!(
+                        "reverted {} -> {}",
+                        escape_path(&entry.new),
+                        escape_path(&entry.old)
+                    );
+                    UndoStatus::Reverted
+                }
+                Err(err) => UndoStatus::Failed(err),
+            }
+        };
+
+        match status {
+            UndoStatus::Reverted => {}
+            UndoStatus::Failed(_) => {
+                pending.push(entry.clone());
+                report.results.push((entry, status));
+                break;
+            }
+            _ => kept.push(entry.clone()),
+        }
+        report.results.push((entry, status));
+    }
+
+    if dry_run {
+        return Ok(report);
+    }
+    // 取り消せなかったエントリは実行順のままジャーナルに残す
+    kept.reverse();
+    pending.extend(kept);
+    if !pending.is_empty() {
+        runs.push(JournalRun {
+            entries: pending,
+            ..run
+        });
+    }
+    save(dir, &runs)?;
+    Ok(report)
+}
@@ -0,0 +1,478 @@
+//! ディレクトリ名から取得したプレフィックスをファイル名に付けるリネームエンジン
+//!
+//! [`RenamePlan`] で対象パス内のリネームを計画し、[`RenamePlan::operations`] で内容を
+//! 確認してから [`RenamePlan::execute`] で実行します。
+//!
+//! ```no_run
+//! use prefix::{RenameOptions, RenamePlan};
+//!
+//! let options = RenameOptions {
+//!     pattern: r"\d{8}".to_string(),
+//!     ..Default::default()
+//! };
+//! let plan = RenamePlan::new("shoots/20241231_sample", &options)?;
+//! for op in plan.operations() {
+//!     println!("{} -> {}", op.src.display(), op.dest.display());
+//! }
+//! let report = plan.execute()?;
+//! println!("{} renamed", report.summary.renamed);
+//! # Ok::<(), prefix::Error>(())
+//! ```
+
+mod encoding;
+pub mod journal;
+mod naming;
+mod normalize;
+mod os_name;
+mod plan;
+mod plan_file;
+pub mod template;
+
+use glob::Pattern;
+use regex::Regex;
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+pub use normalize::is_mixed;
+pub use os_name::{escape_path, from_bytes};
+pub use plan::{Action, Operation, RenamePlan, Report, SkipReason, Status, Summary};
+pub use template::{DirMatch, Template};
+
+/// 再帰時のプレフィックスの取得元
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum PrefixFrom {
+    /// コマンドラインで指定したディレクトリ
+    #[default]
+    Top,
+    /// パターンに一致する最も近い親ディレクトリ
+    Nearest,
+}
+
+/// パターンが対象パスのディレクトリ名に一致しない場合の動作
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum NoMatchPolicy {
+    /// リネームしない（プレフィックスの取得元が最も近い親ディレクトリの場合は一致したサブディレクトリのみリネーム）
+    #[default]
+    Refuse,
+    /// 指定したプレフィックスを使う
+    Prefix(String),
+    /// ディレクトリ名全体をプレフィックスにする
+    Dirname,
+}
+
+/// プレフィックスを挿入する位置
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum Position {
+    /// 名前の先頭（20241231_test.txt）
+    #[default]
+    Prefix,
+    /// --ext-mode で決まる拡張子を除いた名前の末尾（test_20241231.txt, archive_20241231.tar.gz）
+    Suffix,
+    /// --ext-mode で決まる拡張子の直前（suffix と同じ位置）
+    BeforeExt,
+}
+
+/// 拡張子とみなす範囲
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum ExtMode {
+    /// 最後の拡張子（.tar.gz などの既知の複合拡張子は1つとみなす）
+    #[default]
+    Last,
+    /// 最初のドット以降のすべての拡張子
+    All,
+}
+
+/// エントリの種類
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum EntryType {
+    /// 通常のファイル
+    File,
+    /// ディレクトリ
+    Dir,
+    /// シンボリックリンク
+    Symlink,
+}
+
+impl EntryType {
+    /// ファイルの種類からエントリの種類を判定します。シンボリックリンクは辿りません。
+    pub fn of(file_type: &fs::FileType) -> Self {
+        if file_type.is_symlink() {
+            EntryType::Symlink
+        } else if file_type.is_dir() {
+            EntryType::Dir
+        } else {
+            EntryType::File
+        }
+    }
+}
+
+/// リネーム先が衝突した場合の動作
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum ConflictPolicy {
+    /// 何もリネームせずに中止
+    #[default]
+    Abort,
+    /// 衝突したファイルをスキップ
+    Skip,
+    /// 名前の末尾に連番（_1, _2, ...）を付ける
+    Counter,
+    /// 既存のファイルを上書き
+    Overwrite,
+}
+
+/// リネームに失敗した場合の動作
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum FailurePolicy {
+    /// 最初の失敗で中止（それまでのリネームはそのまま残し、ジャーナルに記録する）
+    #[default]
+    Stop,
+    /// 残りの操作も続けて実行し、失敗はすべて結果に含める
+    KeepGoing,
+    /// 最初の失敗で中止し、それまでのリネームを逆順に元に戻す
+    ///
+    /// 上書きしたファイルは元に戻せません。
+    Rollback,
+}
+
+/// 名前の Unicode 正規化形式
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum Normalization {
+    /// 正規化しない
+    #[default]
+    None,
+    /// 合成済みの文字にそろえる（Windows や Linux で一般的）
+    Nfc,
+    /// 分解された文字にそろえる（macOS のファイル名で一般的）
+    Nfd,
+    /// 互換文字も含めて合成済みの文字にそろえる（半角カナは全角になる）
+    Nfkc,
+}
+
+/// UTF-8 でないファイル名の変換元の文字コード
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum SourceEncoding {
+    /// Shift_JIS（CP932 と同じく Windows の拡張を含む）
+    #[cfg_attr(
+        feature = "cli",
+        value(name = "shift_jis", alias = "shift-jis", alias = "sjis")
+    )]
+    ShiftJis,
+    /// EUC-JP
+    EucJp,
+    /// CP932（Windows-31J）
+    Cp932,
+    /// Latin-1（古い Windows で使われていた Windows-1252 として解釈）
+    #[cfg_attr(
+        feature = "cli",
+        value(alias = "iso-8859-1", alias = "windows-1252", alias = "cp1252")
+    )]
+    Latin1,
+}
+
+/// よく使うディレクトリ名の正規表現パターン
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum Preset {
+    /// 8桁の日付（20241231_sample → 20241231）
+    Date8,
+    /// ハイフン区切りの日付（2024-12-31_sample → 2024-12-31）
+    IsoDate,
+    /// 先頭の数字（042_sample → 042）
+    LeadingDigits,
+}
+
+impl Preset {
+    /// 正規表現パターンを返します。日付は year, month, day の名前付きグループを持ちます。
+    pub fn pattern(&self) -> &'static str {
+        match self {
+            Preset::Date8 => r"(?<year>\d{4})(?<month>\d{2})(?<day>\d{2})",
+            Preset::IsoDate => r"(?<year>\d{4})-(?<month>\d{2})-(?<day>\d{2})",
+            Preset::LeadingDigits => r"^\d+",
+        }
+    }
+}
+
+/// 計画ファイルの形式
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum PlanFormat {
+    /// JSON
+    #[default]
+    Json,
+    /// タブ区切り（メタデータは `#` で始まる行）
+    Tsv,
+}
+
+/// リネーム処理のオプション
+#[derive(Clone, Debug)]
+pub struct RenameOptions {
+    /// 正規表現パターン
+    pub pattern: String,
+    /// 対象パスのエントリに付けるプレフィックス（`None` の場合はディレクトリ名から取得）
+    pub prefix: Option<String>,
+    /// 既にプレフィックスが付いているファイルもリネームするか
+    pub reprefix: bool,
+    /// サブディレクトリ内のファイルも再帰的にリネームするか
+    pub recursive: bool,
+    /// 再帰する最大の深さ
+    pub max_depth: Option<usize>,
+    /// 再帰時のプレフィックスの取得元
+    pub prefix_from: PrefixFrom,
+    /// パターンが対象パスのディレクトリ名に一致しない場合の動作
+    pub no_match: NoMatchPolicy,
+    /// リネーム先が衝突した場合の動作
+    pub on_conflict: ConflictPolicy,
+    /// リネームするエントリの種類（空の場合はすべて、再帰時はディレクトリ以外）
+    pub types: Vec<EntryType>,
+    /// 隠しファイルも対象にするか
+    pub include_hidden: bool,
+    /// 対象パス直下でリネームするエントリ名（`None` の場合はすべて、指定した名前は隠しファイルも対象）
+    pub names: Option<HashSet<OsString>>,
+    /// リネームするファイル名のグロブパターン
+    pub include: Vec<Pattern>,
+    /// リネームしないファイル名のグロブパターン
+    pub exclude: Vec<Pattern>,
+    /// リネームするファイル名の正規表現パターン
+    pub include_regex: Vec<Regex>,
+    /// リネームしないファイル名の正規表現パターン
+    pub exclude_regex: Vec<Regex>,
+    /// プレフィックスと元の名前の間の区切り文字
+    pub separator: String,
+    /// プレフィックスを挿入する位置
+    pub position: Position,
+    /// suffix と before-ext で拡張子とみなす範囲（テンプレートの {name} と {ext} にも使用）
+    pub ext_mode: ExtMode,
+    /// リネーム後の名前のテンプレート
+    pub template: Option<Template>,
+    /// プレフィックスを付ける代わりに取り除くか
+    pub strip: bool,
+    /// 取り除くプレフィックスをファイル名の先頭から探す正規表現
+    pub strip_regex: Option<Regex>,
+    /// パターンと照合する前にディレクトリ名に適用する正規化
+    pub normalize: Normalization,
+    /// リネーム後の名前にも正規化を適用するか
+    pub normalize_names: bool,
+    /// UTF-8 でない名前を UTF-8 に変換するときの元の文字コード（`None` の場合はバイト列のまま）
+    pub from_encoding: Option<SourceEncoding>,
+}
+
+impl Default for RenameOptions {
+    fn default() -> Self {
+        RenameOptions {
+            pattern: String::new(),
+            prefix: None,
+            reprefix: false,
+            recursive: false,
+            max_depth: None,
+            prefix_from: PrefixFrom::default(),
+            no_match: NoMatchPolicy::default(),
+            on_conflict: ConflictPolicy::default(),
+            types: Vec::new(),
+            include_hidden: false,
+            names: None,
+            include: Vec::new(),
+            exclude: Vec::new(),
+            include_regex: Vec::new(),
+            exclude_regex: Vec::new(),
+            separator: "_".to_string(),
+            position: Position::default(),
+            ext_mode: ExtMode::default(),
+            template: None,
+            strip: false,
+            strip_regex: None,
+            normalize: Normalization::default(),
+            normalize_names: false,
+            from_encoding: None,
+        }
+    }
+}
+
+impl RenameOptions {
+    /// ファイル名が包含・除外パターンの条件を満たすかを判定します。
+    ///
+    /// 包含パターンが1つも指定されていない場合はすべての名前を含めます。
+    pub fn matches_name(&self, name: &str) -> bool {
+        let included = (self.include.is_empty() && self.include_regex.is_empty())
+            || self.include.iter().any(|p| p.matches(name))
+            || self.include_regex.iter().any(|re| re.is_match(name));
+        let excluded = self.exclude.iter().any(|p| p.matches(name))
+            || self.exclude_regex.iter().any(|re| re.is_match(name));
+        included && !excluded
+    }
+}
+
+/// リネーム処理のエラー
+#[derive(Debug)]
+pub enum Error {
+    /// 正規表現のコンパイルに失敗した
+    Regex(regex::Error),
+    /// ファイル操作に失敗した
+    Io(io::Error),
+    /// 衝突時の動作が中止で、リネーム先が衝突した（衝突した操作の一覧）
+    Conflicts(Vec<Operation>),
+    /// 計画作成後にリネーム元が変更された、またはリネーム先が使われた（該当する操作の一覧）
+    Stale(Vec<Operation>),
+    /// リネーム後の名前にパス区切り文字が含まれるなど、ファイル名として使えない（該当する操作の一覧）
+    InvalidNames(Vec<Operation>),
+    /// パターンが対象パスのディレクトリ名に一致せず、リネームしなかった（対象パス）
+    NoMatch(PathBuf),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Regex(err) => write!(f, "{}", err),
+            Error::Io(err) => write!(f, "{}", err),
+            Error::Conflicts(ops) => {
+                write!(f, "{} conflicting renames, nothing was renamed", ops.len())
+            }
+            Error::Stale(ops) => write!(
+                f,
+                "{} planned renames no longer match the disk, nothing was renamed",
+                ops.len()
+            ),
+            Error::InvalidNames(ops) => write!(
+                f,
+                "{} renames would not produce a plain file name, nothing was renamed",
+                ops.len()
+            ),
+            Error::NoMatch(path) => write!(
+                f,
+                "pattern did not match the directory name of {}",
+                escape_path(path)
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Regex(err) => Some(err),
+            Error::Io(err) => Some(err),
+            Error::Conflicts(_) | Error::Stale(_) | Error::InvalidNames(_) | Error::NoMatch(_) => {
+                None
+            }
+        }
+    }
+}
+
+impl From<regex::Error> for Error {
+    fn from(err: regex::Error) -> Self {
+        Error::Regex(err)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+/// 正規表現パターンに基づいてディレクトリ名からプレフィックスを取得します。
+///
+/// # Arguments
+///
+/// * `pattern` - 正規表現パターン
+/// * `dirname` - ディレクトリ名
+///
+/// # Returns
+///
+/// プレフィックス文字列
+///
+/// # Errors
+///
+/// 正規表現のコンパイルに失敗した場合
+pub fn get_prefix(pattern: &str, dirname: &str) -> Result<String, regex::Error> {
+    let re = compile_pattern(pattern)?;
+    Ok(DirMatch::capture(&re, dirname).prefix)
+}
+
+/// 正規表現パターンをコンパイルします。空のパターンはディレクトリ名全体に一致します。
+///
+/// # Errors
+///
+/// 正規表現のコンパイルに失敗した場合
+pub fn compile_pattern(pattern: &str) -> Result<Regex, regex::Error> {
+    Regex::new(if pattern.is_empty() { r".*" } else { pattern })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_get_prefix() {
+        let pattern = r"\d+";
+        let dirname = "20241231_sample";
+        let prefix = get_prefix(pattern, dirname).unwrap();
+        assert_eq!(prefix, "20241231");
+    }
+
+    #[test]
+    fn test_get_prefix_long_path() {
+        let pattern = r"\d+";
+        let dirname = "sample/20241231_sample";
+        let prefix = get_prefix(pattern, dirname).unwrap();
+        assert_eq!(prefix, "20241231");
+    }
+
+    #[test]
+    fn test_get_prefix_empty_pattern() {
+        let pattern = "";
+        let dirname = "20241231_sample";
+        let prefix = get_prefix(pattern, dirname).unwrap();
+        assert_eq!(prefix, "20241231_sample");
+    }
+
+    #[test]
+    fn test_get_prefix_presets() {
+        let prefix = |preset: Preset, dirname| get_prefix(preset.pattern(), dirname).unwrap();
+        assert_eq!(prefix(Preset::Date8, "shoot_20241231"), "20241231");
+        assert_eq!(prefix(Preset::IsoDate, "2024-12-31_sample"), "2024-12-31");
+        assert_eq!(prefix(Preset::LeadingDigits, "042_sample_2024"), "042");
+        assert_eq!(prefix(Preset::LeadingDigits, "sample_2024"), "");
+    }
+
+    #[test]
+    fn test_invalid_regex() {
+        let pattern = r"(\d+";
+        let dirname = "20241231_sample";
+        let result = get_prefix(pattern, dirname);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_matches_name() {
+        let filters = RenameOptions {
+            include: vec![
+                Pattern::new("*.jpg").unwrap(),
+                Pattern::new("*.mp4").unwrap(),
+            ],
+            exclude_regex: vec![Regex::new(r"^tmp_").unwrap()],
+            ..Default::default()
+        };
+        assert!(filters.matches_name("a.jpg"));
+        assert!(filters.matches_name("b.mp4"));
+        assert!(!filters.matches_name("a.xmp"));
+        assert!(!filters.matches_name("tmp_a.jpg"));
+
+        let filters = RenameOptions {
+            exclude: vec![Pattern::new("Thumbs.db").unwrap()],
+            ..Default::default()
+        };
+        assert!(filters.matches_name("a.xmp"));
+        assert!(!filters.matches_name("Thumbs.db"));
+    }
+}
@@ -1,141 +1,1294 @@
-use clap::Parser;
+mod output;
+
+use clap::{ArgAction, ArgGroup, Args, Parser, Subcommand};
+use glob::Pattern;
+use log::{debug, warn, LevelFilter};
+use output::{lossy_mark, OutputFormat, Printer};
+use prefix::journal::{self, UndoStatus};
+use prefix::{
+    compile_pattern, escape_path, from_bytes, ConflictPolicy, EntryType, Error, ExtMode,
+    FailurePolicy, NoMatchPolicy, Normalization, PlanFormat, Position, PrefixFrom, Preset,
+    RenameOptions, RenamePlan, SourceEncoding, Status, Summary, Template,
+};
 use regex::Regex;
-use std::fs;
-use std::path::Path;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashSet};
+use std::ffi::OsString;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
 
 #[derive(Parser)]
+#[clap(
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true,
+    after_help = "Exit codes:\n  \
+        0  success\n  \
+        1  other error (reading or writing a plan file or the journal)\n  \
+        2  invalid arguments\n  \
+        3  regex compile error\n  \
+        4  the pattern did not match the directory name\n  \
+        5  partial failure (some files were processed)\n  \
+        6  total failure (nothing was processed)"
+)]
 struct Cli {
-    /// 対象パス
-    path: String,
+    #[clap(subcommand)]
+    command: Option<Command>,
+
+    #[clap(flatten)]
+    add: AddArgs,
 
-    /// 正規表現パターン
+    /// ログを詳しく表示（-v で info、-vv で debug、-vvv で trace）
+    #[clap(short = 'v', long = "verbose", action = ArgAction::Count, global = true)]
+    verbose: u8,
+
+    /// ログを抑制（-q で error のみ、-qq で表示しない）
+    #[clap(
+        short = 'q',
+        long = "quiet",
+        action = ArgAction::Count,
+        global = true,
+        conflicts_with = "verbose"
+    )]
+    quiet: u8,
+}
+
+impl Cli {
+    /// -v と -q の指定回数からログレベルを決めます。既定は warn です。
+    fn log_level(&self) -> LevelFilter {
+        match i16::from(self.verbose) - i16::from(self.quiet) {
+            ..=-2 => LevelFilter::Off,
+            -1 => LevelFilter::Error,
+            0 => LevelFilter::Warn,
+            1 => LevelFilter::Info,
+            2 => LevelFilter::Debug,
+            _ => LevelFilter::Trace,
+        }
+    }
+}
+
+/// プレフィックスを付ける場合の引数
+#[derive(Args)]
+#[clap(group(ArgGroup::new("prefix_source").args(["pattern", "preset", "prefix"])))]
+struct AddArgs {
+    /// 対象パス（複数指定可、`shoots/2024*` のようなグロブは一致するディレクトリに展開）
+    #[clap(required_unless_present = "stdin")]
+    paths: Vec<String>,
+
+    /// リネームするファイルのパスを標準入力から読み込む
+    ///
+    /// 各ファイルのプレフィックスはそれぞれの親ディレクトリ名から取得します。
+    #[clap(long = "stdin", conflicts_with_all = ["paths", "recursive"])]
+    stdin: bool,
+
+    /// 標準入力のパスを改行ではなく NUL 文字で区切る（`find -print0` や `fd -0` の出力用）
+    #[clap(short = '0', long = "null")]
+    null: bool,
+
+    /// ディレクトリ名からプレフィックスを取得する正規表現パターン（省略時はディレクトリ名全体）
     #[clap(short = 'e')]
-    pattern: String,
+    pattern: Option<String>,
+
+    /// -e の代わりに使う組み込みのパターン
+    #[clap(long = "preset", value_enum)]
+    preset: Option<Preset>,
 
+    /// ディレクトリ名を使わずにすべてのファイルに付けるプレフィックス（例: PRJ042）
+    #[clap(
+        long = "prefix",
+        value_parser = parse_prefix,
+        conflicts_with_all = ["default_prefix", "fallback_dirname", "prefix_from"]
+    )]
+    prefix: Option<String>,
+
+    /// 既にプレフィックスが付いているファイルにも重ねて付ける
+    #[clap(long = "reprefix")]
+    reprefix: bool,
+
+    /// パターンがディレクトリ名に一致しない場合に使うプレフィックス
+    ///
+    /// 指定しない場合、一致しなければ何もリネームせずに終了します。
+    #[clap(
+        long = "default-prefix",
+        value_parser = parse_prefix,
+        conflicts_with = "fallback_dirname"
+    )]
+    default_prefix: Option<String>,
+
+    /// パターンがディレクトリ名に一致しない場合はディレクトリ名全体をプレフィックスにする
+    #[clap(long = "fallback-dirname")]
+    fallback_dirname: bool,
+
+    /// プレフィックスと元の名前の間の区切り文字（空文字列も可）
+    #[clap(
+        long = "separator",
+        default_value = "_",
+        allow_hyphen_values = true,
+        value_parser = parse_name_part
+    )]
+    separator: String,
+
+    /// プレフィックスを挿入する位置
+    #[clap(long = "position", value_enum, default_value_t)]
+    position: Position,
+
+    /// suffix と before-ext で拡張子とみなす範囲
+    #[clap(long = "ext-mode", value_enum, default_value_t)]
+    ext_mode: ExtMode,
+
+    /// リネーム後の名前のテンプレート（例: '{1}-{2}-{3}_{name}{ext}'）
+    ///
+    /// {0}, {1}, ... と {year} などはパターンのキャプチャグループ、{prefix} はプレフィックス、
+    /// {name} は拡張子を除いた元の名前、{ext} は元の拡張子、{counter} または {counter:3}
+    /// は1から始まる連番に置き換えられます。
+    #[clap(
+        long = "template",
+        value_parser = Template::parse,
+        conflicts_with_all = ["position", "separator"]
+    )]
+    template: Option<Template>,
+
+    #[clap(flatten)]
+    select: SelectArgs,
+}
+
+/// 対象エントリの選択とリネームの実行に関する共通の引数
+#[derive(Args)]
+struct SelectArgs {
     /// ファイルをリネームせずに実行結果を表示
     #[clap(short = 'd', long = "dry_run")]
     dry_run: bool,
+
+    #[clap(flatten)]
+    run: RunArgs,
+
+    /// サブディレクトリ内のファイルも再帰的にリネーム
+    #[clap(short = 'r', long = "recursive")]
+    recursive: bool,
+
+    /// 再帰する最大の深さ（1 は直下のみ）
+    #[clap(long = "max-depth", requires = "recursive")]
+    max_depth: Option<usize>,
+
+    /// 再帰時のプレフィックスの取得元
+    #[clap(
+        long = "prefix-from",
+        value_enum,
+        default_value_t,
+        requires = "recursive"
+    )]
+    prefix_from: PrefixFrom,
+
+    /// リネーム先が衝突した場合の動作
+    #[clap(long = "on-conflict", value_enum, default_value_t)]
+    on_conflict: ConflictPolicy,
+
+    /// リネームするエントリの種類（複数指定可）
+    #[clap(long = "type", value_enum, value_delimiter = ',')]
+    types: Vec<EntryType>,
+
+    /// ドットで始まる隠しファイルも対象にする
+    #[clap(long = "include-hidden")]
+    include_hidden: bool,
+
+    /// パターンと照合する前にディレクトリ名に適用する Unicode 正規化
+    ///
+    /// macOS からコピーしたファイル名は NFD のことが多く、見た目が同じでも一致しません。
+    #[clap(long = "normalize", value_enum, default_value_t)]
+    normalize: Normalization,
+
+    /// リネーム後の名前にも --normalize の正規化を適用する
+    #[clap(long = "normalize-names", requires = "normalize")]
+    normalize_names: bool,
+
+    /// UTF-8 でないファイル名をこの文字コードとして解釈し、リネーム後の名前を UTF-8 で書く
+    ///
+    /// 解釈できない名前はスキップします。UTF-8 として正しい名前は変換しません。
+    #[clap(long = "from-encoding", value_enum)]
+    from_encoding: Option<SourceEncoding>,
+
+    /// リネームするファイル名のグロブパターン（複数指定可）
+    #[clap(long = "include", value_parser = Pattern::new)]
+    include: Vec<Pattern>,
+
+    /// リネームしないファイル名のグロブパターン（複数指定可）
+    #[clap(long = "exclude", value_parser = Pattern::new)]
+    exclude: Vec<Pattern>,
+
+    /// リネームするファイル名の正規表現パターン（複数指定可）
+    #[clap(long = "include-regex")]
+    include_regex: Vec<String>,
+
+    /// リネームしないファイル名の正規表現パターン（複数指定可）
+    #[clap(long = "exclude-regex")]
+    exclude_regex: Vec<String>,
 }
 
-/// 正規表現パターンに基づいてディレクトリ名からプレフィックスを取得します。
+/// リネームの実行と結果の表示に関する引数
+#[derive(Args, Clone, Copy)]
+struct RunArgs {
+    /// 実行結果の出力形式
+    #[clap(long = "format", value_enum, default_value_t)]
+    format: OutputFormat,
+
+    /// リネームに失敗しても残りのファイルを続けて処理し、最後に失敗の一覧を表示
+    ///
+    /// 指定しない場合は最初の失敗で中止します。それまでのリネームは元に戻さずに
+    /// ジャーナルへ記録するため、undo で取り消せます。
+    #[clap(long = "keep-going")]
+    keep_going: bool,
+
+    /// リネームに失敗した場合、それまでのリネームを逆順に元に戻して何も変更しない
+    ///
+    /// 元に戻せなかったリネームは一覧を表示し、ジャーナルに残します。
+    /// 上書きしたファイルは元に戻せません。
+    #[clap(long = "atomic", conflicts_with = "keep_going")]
+    atomic: bool,
+}
+
+impl RunArgs {
+    /// リネームに失敗した場合の動作を返します。
+    fn failure_policy(&self) -> FailurePolicy {
+        if self.keep_going {
+            FailurePolicy::KeepGoing
+        } else if self.atomic {
+            FailurePolicy::Rollback
+        } else {
+            FailurePolicy::Stop
+        }
+    }
+}
+
+impl SelectArgs {
+    /// 共通の引数からリネームオプションを作成します。
+    ///
+    /// ドライランフラグはリネームオプションに含まれないため、呼び出し側で扱います。
+    ///
+    /// # Errors
+    ///
+    /// 正規表現のコンパイルに失敗した場合
+    fn into_options(self) -> Result<RenameOptions, CliError> {
+        let compile = |patterns: Vec<String>| {
+            patterns
+                .iter()
+                .map(|pattern| Regex::new(pattern))
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(CliError::Regex)
+        };
+        Ok(RenameOptions {
+            recursive: self.recursive,
+            max_depth: self.max_depth,
+            prefix_from: self.prefix_from,
+            on_conflict: self.on_conflict,
+            types: self.types,
+            include_hidden: self.include_hidden,
+            include: self.include,
+            exclude: self.exclude,
+            include_regex: compile(self.include_regex)?,
+            exclude_regex: compile(self.exclude_regex)?,
+            normalize: self.normalize,
+            normalize_names: self.normalize_names,
+            from_encoding: self.from_encoding,
+            ..Default::default()
+        })
+    }
+}
+
+/// プレフィックスを取り除く場合の引数
+#[derive(Args)]
+#[clap(group(ArgGroup::new("source").required(true).args(["pattern", "regex"])))]
+struct StripArgs {
+    /// 対象パス
+    path: String,
+
+    /// ディレクトリ名からプレフィックスを取得する正規表現パターン
+    #[clap(short = 'e')]
+    pattern: Option<String>,
+
+    /// ファイル名の先頭から取り除くプレフィックスの正規表現パターン
+    #[clap(
+        long = "regex",
+        conflicts_with_all = ["position", "prefix_from"]
+    )]
+    regex: Option<String>,
+
+    /// プレフィックスと元の名前の間の区切り文字（空文字列も可）
+    #[clap(
+        long = "separator",
+        default_value = "_",
+        allow_hyphen_values = true,
+        value_parser = parse_name_part
+    )]
+    separator: String,
+
+    /// プレフィックスが挿入されている位置
+    #[clap(long = "position", value_enum, default_value_t)]
+    position: Position,
+
+    /// suffix と before-ext で拡張子とみなす範囲
+    #[clap(long = "ext-mode", value_enum, default_value_t)]
+    ext_mode: ExtMode,
+
+    #[clap(flatten)]
+    select: SelectArgs,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// 直前のリネームをジャーナルに基づいて元に戻す
+    Undo {
+        /// 対象パス
+        path: String,
+
+        /// ファイルをリネームせずに実行結果を表示
+        #[clap(short = 'd', long = "dry_run")]
+        dry_run: bool,
+    },
+
+    /// 以前に付けたプレフィックスを取り除く
+    Strip(Box<StripArgs>),
+
+    /// プレフィックスを付けるリネームを計画し、計画ファイルに書き出す
+    Plan(Box<PlanArgs>),
+
+    /// 計画ファイルのリネームを実行する
+    Apply {
+        /// 計画ファイル
+        plan: PathBuf,
+
+        #[clap(flatten)]
+        run: RunArgs,
+    },
+}
+
+/// 計画ファイルを作成する場合の引数
+#[derive(Args)]
+struct PlanArgs {
+    /// 計画ファイルの出力先（省略時は標準出力）
+    #[clap(short = 'o', long = "output")]
+    output: Option<PathBuf>,
+
+    /// 計画ファイルの形式
+    #[clap(long = "plan-format", value_enum, default_value_t)]
+    plan_format: PlanFormat,
+
+    #[clap(flatten)]
+    add: AddArgs,
+}
+
+/// コマンドの失敗
 ///
-/// # Arguments
+/// 終了コードは [`CliError::exit_code`] で決まります。
+#[derive(Debug)]
+enum CliError {
+    /// 引数が不正
+    InvalidArgs(String),
+    /// 正規表現のコンパイルに失敗した
+    Regex(regex::Error),
+    /// パターンがディレクトリ名に一致しなかった（対象パス）
+    NoMatch(PathBuf),
+    /// リネームに失敗した（一部のファイルはリネーム済みか）
+    Rename { partial: bool, source: Error },
+    /// 続行モードで一部のリネームに失敗した
+    Failures { renamed: usize, failed: usize },
+    /// リネームに失敗し、その対象パスのリネームをすべて元に戻した（他の対象パスはリネーム済みか）
+    RolledBack {
+        partial: bool,
+        rolled_back: usize,
+        source: Error,
+    },
+    /// リネームに失敗し、元に戻せなかったリネームがある
+    RollbackFailed { failed: usize, source: Error },
+    /// 取り消しに失敗した（一部のファイルは元に戻したか）
+    Undo { partial: bool, source: io::Error },
+    /// 変更が検出された、または元の名前が使われていたため取り消せなかった
+    Refused { reverted: usize, refused: usize },
+    /// その他の失敗（計画ファイルやジャーナルの読み書きなど）
+    Other {
+        context: &'static str,
+        source: Error,
+    },
+}
+
+impl CliError {
+    /// 一般的なエラー
+    const EXIT_OTHER: u8 = 1;
+    /// 引数が不正（clap の引数エラーと同じ）
+    const EXIT_INVALID_ARGS: u8 = 2;
+    /// 正規表現のコンパイルエラー
+    const EXIT_REGEX: u8 = 3;
+    /// パターンがディレクトリ名に一致しない
+    const EXIT_NO_MATCH: u8 = 4;
+    /// 一部のファイルだけ処理して失敗
+    const EXIT_PARTIAL: u8 = 5;
+    /// 1つも処理できずに失敗
+    const EXIT_FAILED: u8 = 6;
+
+    /// 終了コードを返します。
+    fn exit_code(&self) -> u8 {
+        let failed = |partial: bool| {
+            if partial {
+                Self::EXIT_PARTIAL
+            } else {
+                Self::EXIT_FAILED
+            }
+        };
+        match self {
+            CliError::InvalidArgs(_)
+            | CliError::Rename {
+                source: Error::InvalidNames(_),
+                ..
+            } => Self::EXIT_INVALID_ARGS,
+            CliError::Regex(_)
+            | CliError::Rename {
+                source: Error::Regex(_),
+                ..
+            } => Self::EXIT_REGEX,
+            CliError::NoMatch(_) => Self::EXIT_NO_MATCH,
+            CliError::Rename { partial, .. } | CliError::Undo { partial, .. } => failed(*partial),
+            CliError::Failures { renamed, .. } => failed(*renamed > 0),
+            CliError::RolledBack { partial, .. } => failed(*partial),
+            CliError::RollbackFailed { .. } => Self::EXIT_PARTIAL,
+            CliError::Refused { reverted, .. } => failed(*reverted > 0),
+            CliError::Other { .. } => Self::EXIT_OTHER,
+        }
+    }
+
+    /// 先に処理した対象パスで `renamed` 件をリネーム済みの場合の失敗に変換します。
+    fn after(self, renamed: usize) -> Self {
+        match self {
+            CliError::Rename { partial, source } => CliError::Rename {
+                partial: partial || renamed > 0,
+                source,
+            },
+            CliError::Failures {
+                renamed: current,
+                failed,
+            } => CliError::Failures {
+                renamed: current + renamed,
+                failed,
+            },
+            CliError::RolledBack {
+                partial,
+                rolled_back,
+                source,
+            } => CliError::RolledBack {
+                partial: partial || renamed > 0,
+                rolled_back,
+                source,
+            },
+            err => err,
+        }
+    }
+}
+
+impl fmt::Display for CliError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CliError::InvalidArgs(message) => write!(f, "{}", message),
+            CliError::Regex(err) => write!(f, "Error compiling regex: {}", err),
+            CliError::NoMatch(path) => write!(
+                f,
+                "Pattern did not match the directory name of {}",
+                escape_path(path)
+            ),
+            CliError::Rename { source, .. } => write!(f, "Error renaming files: {}", source),
+            CliError::Failures { failed, .. } => write!(f, "{} renames failed", failed),
+            CliError::RolledBack {
+                rolled_back,
+                source,
+                ..
+            } => write!(
+                f,
+                "Error renaming files: {}; {} completed renames were rolled back",
+                source, rolled_back
+            ),
+            CliError::RollbackFailed { failed, source } => write!(
+                f,
+                "Error renaming files: {}; {} renames could not be rolled back",
+                source, failed
+            ),
+            CliError::Undo { source, .. } => write!(f, "Error undoing renames: {}", source),
+            CliError::Refused { refused, .. } => {
+                write!(f, "{} renames could not be undone", refused)
+            }
+            CliError::Other { context, source } => write!(f, "{}: {}", context, source),
+        }
+    }
+}
+
+/// ファイル名に埋め込む値（区切り文字など）を検証します（clap の value_parser 用）。
 ///
-/// * `pattern` - 正規表現パターン
-/// * `dirname` - ディレクトリ名
+/// # Errors
 ///
-/// # Returns
+/// パス区切り文字または NUL 文字を含む場合（ファイルが別のディレクトリへ移動するため）
+fn parse_name_part(value: &str) -> Result<String, String> {
+    if value.contains(|c| c == '\0' || std::path::is_separator(c)) {
+        return Err("must not contain a path separator or NUL".to_string());
+    }
+    Ok(value.to_string())
+}
+
+/// コマンドラインで指定したプレフィックスを検証します（clap の value_parser 用）。
+///
+/// # Errors
+///
+/// 空の場合、またはパス区切り文字か NUL 文字を含む場合
+fn parse_prefix(value: &str) -> Result<String, String> {
+    if value.is_empty() {
+        return Err("must not be empty".to_string());
+    }
+    parse_name_part(value)
+}
+
+/// 計画したリネームを実行せずに各操作を表示し、集計を `total` に加算します。
+fn preview(plan: &RenamePlan, printer: &mut Printer, total: &mut Summary) {
+    for op in plan.operations() {
+        printer.operation(op, None);
+    }
+    *total += &plan.summary();
+}
+
+/// 計画を実行して各操作の結果を表示し、集計を `total` に加算します。
 ///
-/// プレフィックス文字列
+/// # Errors
+///
+/// 計画が無効になっていた場合、またはリネームに失敗した場合
+fn execute(
+    plan: &RenamePlan,
+    printer: &mut Printer,
+    on_failure: FailurePolicy,
+    total: &mut Summary,
+) -> Result<(), CliError> {
+    let report = plan
+        .execute_with(on_failure)
+        .map_err(|source| CliError::Rename {
+            partial: false,
+            source,
+        })?;
+    let summary = report.summary;
+    let rollback_failed = report
+        .rollback
+        .iter()
+        .filter(|(_, result)| result.is_err())
+        .count();
+    let mut result = Ok(());
+    for (op, status) in report.results {
+        match status {
+            Status::Failed(err) => {
+                printer.operation(&op, Some(&err));
+                result = Err(match on_failure {
+                    FailurePolicy::Stop => CliError::Rename {
+                        partial: summary.renamed > 0,
+                        source: err.into(),
+                    },
+                    FailurePolicy::KeepGoing => CliError::Failures {
+                        renamed: summary.renamed,
+                        failed: summary.failed,
+                    },
+                    FailurePolicy::Rollback if rollback_failed > 0 => CliError::RollbackFailed {
+                        failed: rollback_failed,
+                        source: err.into(),
+                    },
+                    FailurePolicy::Rollback => CliError::RolledBack {
+                        partial: false,
+                        rolled_back: summary.rolled_back,
+                        source: err.into(),
+                    },
+                });
+            }
+            _ => printer.operation(&op, None),
+        }
+    }
+    for (op, result) in &report.rollback {
+        printer.rollback(
+            op,
+            result.as_ref().err().map(|err| err as &dyn fmt::Display),
+        );
+    }
+    *total += &summary;
+    result
+}
+
+/// 計画が拒否された原因の操作を表示し、出力を終えます。
+fn finish(mut printer: Printer, result: Result<(), CliError>) -> Result<(), CliError> {
+    if let Err(CliError::Rename { source, .. }) = &result {
+        match source {
+            Error::Conflicts(ops) => {
+                for op in ops {
+                    printer.rejected(op, "Conflict", "destination already exists or is claimed");
+                }
+            }
+            Error::Stale(ops) => {
+                for op in ops {
+                    printer.rejected(op, "Stale", "changed since the plan was made");
+                }
+            }
+            Error::InvalidNames(ops) => {
+                for op in ops {
+                    printer.rejected(op, "Invalid name", "destination is not a plain file name");
+                }
+            }
+            _ => {}
+        }
+    }
+    printer.finish();
+    result
+}
+
+/// 対象パス内のファイルの計画を作成します。
 ///
 /// # Errors
 ///
-/// 正規表現のコンパイルに失敗した場合
-fn get_prefix(pattern: &str, dirname: &str) -> Result<String, regex::Error> {
-    let re = Regex::new(if pattern.is_empty() { r".*" } else { pattern })?;
-    Ok(re
-        .captures(dirname)
-        .and_then(|caps| caps.get(0))
-        .map_or_else(|| "".to_string(), |m| m.as_str().to_string()))
+/// 計画の作成に失敗した場合（何もリネームしていない）
+fn new_plan(path: &Path, options: &RenameOptions) -> Result<RenamePlan, CliError> {
+    RenamePlan::new(path, options).map_err(|source| match source {
+        Error::NoMatch(path) => CliError::NoMatch(path),
+        source => CliError::Rename {
+            partial: false,
+            source,
+        },
+    })
 }
 
-/// 指定されたパス内のファイルをリネームします。
+/// 対象パスごとにファイルをリネームし、対象パスごとの結果と全体の集計を表示します。
+///
+/// すべての対象パスのリネームを計画してから実行するため、計画時の衝突により中止した場合は
+/// 何も変更しません。実行中に失敗した場合、それまでの対象パスのリネームはそのまま残ります。
+/// 続行モードでは失敗した対象パスの後も残りの対象パスを処理します。
+/// 失敗時に元に戻すのは、失敗した対象パスのリネームのみです。
+fn run(
+    targets: &[(PathBuf, RenameOptions)],
+    dry_run: bool,
+    run_args: RunArgs,
+) -> Result<(), CliError> {
+    let mut printer = Printer::new(run_args.format);
+    let grouped = targets.len() > 1;
+    let mut plans = Vec::new();
+    for (path, options) in targets {
+        match new_plan(path, options) {
+            Ok(plan) => plans.push(plan),
+            Err(err) => {
+                printer.group(path, grouped);
+                return finish(printer, Err(err));
+            }
+        }
+    }
+
+    let on_failure = run_args.failure_policy();
+    let mut total = Summary::default();
+    let mut result = Ok(());
+    for (plan, (path, _)) in plans.iter().zip(targets) {
+        printer.group(path, grouped);
+        if dry_run {
+            preview(plan, &mut printer, &mut total);
+            continue;
+        }
+        let renamed = total.renamed;
+        if let Err(err) = execute(plan, &mut printer, on_failure, &mut total) {
+            if result.is_ok() {
+                result = Err(err.after(renamed));
+            }
+            if on_failure != FailurePolicy::KeepGoing {
+                break;
+            }
+        }
+    }
+    printer.summary(&total);
+    if on_failure == FailurePolicy::KeepGoing && result.is_err() && total.failed > 0 {
+        result = Err(CliError::Failures {
+            renamed: total.renamed,
+            failed: total.failed,
+        });
+    }
+    finish(printer, result)
+}
+
+/// 対象パスが存在するディレクトリであることを確認します。
+///
+/// # Errors
+///
+/// 対象パスが存在しない場合、またはディレクトリでない場合
+fn check_target(path: &Path) -> Result<(), CliError> {
+    if !path.exists() {
+        return Err(CliError::InvalidArgs(format!(
+            "{} does not exist",
+            escape_path(path)
+        )));
+    }
+    if !path.is_dir() {
+        return Err(CliError::InvalidArgs(format!(
+            "{} is not a directory",
+            escape_path(path)
+        )));
+    }
+    Ok(())
+}
+
+/// 対象パスの引数を展開します。
+///
+/// 存在しないパスにグロブの特殊文字が含まれる場合は、一致するディレクトリに展開します。
+/// 同じディレクトリを指すパスは1つにまとめ、入れ子の対象パスは中身が先にリネームされるよう
+/// 深いものから順に並べます。
 ///
 /// # Arguments
 ///
-/// * `path` - 対象パス
-/// * `prefix` - プレフィックス
-/// * `dry_run` - ドライランフラグ
+/// * `args` - 対象パスの引数
+/// * `recursive` - サブディレクトリ内も再帰的にリネームするか
+///
+/// # Errors
+///
+/// グロブパターンが不正な場合、一致するディレクトリがない場合、
+/// または再帰モードで対象パスが別の対象パスの中にある場合
+fn expand_paths(args: &[String], recursive: bool) -> Result<Vec<PathBuf>, CliError> {
+    let mut paths = Vec::new();
+    for arg in args {
+        let path = PathBuf::from(arg);
+        if path.exists() || !arg.contains(['*', '?', '[']) {
+            check_target(&path)?;
+            paths.push(path);
+            continue;
+        }
+        let matches = glob::glob(arg)
+            .map_err(|err| CliError::InvalidArgs(format!("Invalid glob '{}': {}", arg, err)))?
+            .filter_map(Result::ok)
+            .filter(|path| path.is_dir())
+            .collect::<Vec<_>>();
+        if matches.is_empty() {
+            return Err(CliError::InvalidArgs(format!(
+                "No directories match '{}'",
+                arg
+            )));
+        }
+        debug!("{} matched {} directories", arg, matches.len());
+        paths.extend(matches);
+    }
+
+    // 同じファイルを2つの計画でリネームしないよう、実際のパスで重複と入れ子を確認する
+    let mut targets: Vec<(PathBuf, PathBuf)> = Vec::new();
+    for path in paths {
+        let canonical = fs::canonicalize(&path).unwrap_or_else(|_| path.clone());
+        if targets.iter().any(|(seen, _)| *seen == canonical) {
+            debug!("{} is already a target", path.display());
+            continue;
+        }
+        targets.push((canonical, path));
+    }
+    if recursive {
+        for (outer, outer_path) in &targets {
+            let nested = targets
+                .iter()
+                .find(|(inner, _)| inner != outer && inner.starts_with(outer));
+            if let Some((_, inner_path)) = nested {
+                return Err(CliError::InvalidArgs(format!(
+                    "{} is inside {}, which is already renamed recursively",
+                    inner_path.display(),
+                    outer_path.display()
+                )));
+            }
+        }
+    }
+    targets.sort_by_key(|(canonical, _)| Reverse(canonical.components().count()));
+    Ok(targets.into_iter().map(|(_, path)| path).collect())
+}
+
+/// 標準入力から読み込んだファイルのパスを親ディレクトリごとにまとめます。
+///
+/// 同じディレクトリを1つにまとめるため、親ディレクトリは絶対パスに変換します。
+/// `find` の出力に含まれるディレクトリ自体が中身より先にリネームされないよう、
+/// 深いディレクトリから順に並べます。
+/// `.` のようにファイル名を持たないパスと、存在しないパスはスキップします。
+///
+/// # Arguments
+///
+/// * `input` - 標準入力の内容
+/// * `delimiter` - パスの区切り文字（NUL 文字または改行）
 ///
 /// # Returns
 ///
-/// 処理結果
+/// 親ディレクトリと、その中でリネームするエントリ名
 ///
 /// # Errors
 ///
-/// ファイルのリネームに失敗した場合
-fn rename_files(path: &Path, prefix: &str, dry_run: bool) -> std::io::Result<()> {
-    for entry in fs::read_dir(path)? {
-        let entry = entry?;
-        let filename = entry.file_name();
-        let src_name = filename.to_string_lossy();
-        let dest_name = format!("{}_{}", prefix, src_name);
-        println!("{} -> {}", src_name, dest_name);
-
-        if !dry_run {
-            fs::rename(entry.path(), path.join(dest_name))?;
+/// 親ディレクトリを解決できない場合、またはリネームするパスが1つもない場合
+fn group_by_parent(
+    input: &[u8],
+    delimiter: u8,
+) -> Result<Vec<(PathBuf, HashSet<OsString>)>, CliError> {
+    let mut targets = BTreeMap::<PathBuf, HashSet<OsString>>::new();
+    for line in input
+        .split(|&b| b == delimiter)
+        .filter(|line| !line.is_empty())
+    {
+        let path = PathBuf::from(from_bytes(line.to_vec()));
+        let Some(name) = path.file_name() else {
+            // `find .` は作業ディレクトリ自体を最初に出力する
+            debug!("{} has no file name, skipped", escape_path(&path));
+            continue;
+        };
+        if fs::symlink_metadata(&path).is_err() {
+            warn!("{} does not exist, skipped", escape_path(&path));
+            continue;
         }
+        let parent = path
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+            .unwrap_or(Path::new("."));
+        let parent = fs::canonicalize(parent).map_err(|err| CliError::Other {
+            context: "Error resolving the parent directory",
+            source: err.into(),
+        })?;
+        targets
+            .entry(parent)
+            .or_default()
+            .insert(name.to_os_string());
+    }
+    if targets.is_empty() {
+        return Err(CliError::InvalidArgs(
+            "No files to rename were read from standard input".to_string(),
+        ));
+    }
+    let mut targets = targets.into_iter().collect::<Vec<_>>();
+    targets.sort_by_key(|(parent, _)| Reverse(parent.components().count()));
+    Ok(targets)
+}
+
+/// プレフィックスを付ける場合の引数から、対象パスごとのリネームオプションを作成します。
+///
+/// プレフィックスは対象パスごとに、それぞれのディレクトリ名から取得します。
+///
+/// # Returns
+///
+/// 対象パスとリネームオプションの一覧
+///
+/// # Errors
+///
+/// 対象パスやテンプレートが不正な場合、正規表現のコンパイルに失敗した場合、
+/// またはパターンが一致しない対象パスがある場合（何もリネームしない）
+fn add_targets(args: AddArgs) -> Result<Vec<(PathBuf, RenameOptions)>, CliError> {
+    // サブコマンドがない場合、--stdin を指定しなければ paths は clap により必須となる
+    // --stdin は paths と競合するため、clap の requires では -0 だけの指定を検出できない
+    if args.null && !args.stdin {
+        return Err(CliError::InvalidArgs("-0 requires --stdin".to_string()));
+    }
+    let paths = if args.stdin {
+        let mut input = Vec::new();
+        io::stdin()
+            .lock()
+            .read_to_end(&mut input)
+            .map_err(|err| CliError::Other {
+                context: "Error reading standard input",
+                source: err.into(),
+            })?;
+        let delimiter = if args.null { b'\0' } else { b'\n' };
+        group_by_parent(&input, delimiter)?
+            .into_iter()
+            .map(|(path, names)| (path, Some(names)))
+            .collect()
+    } else {
+        expand_paths(&args.paths, args.select.recursive)?
+            .into_iter()
+            .map(|path| (path, None))
+            .collect::<Vec<_>>()
+    };
+    let pattern = match args.preset {
+        Some(preset) => preset.pattern().to_string(),
+        None => args.pattern.unwrap_or_default(),
+    };
+
+    // テンプレートが参照するキャプチャグループを検証
+    if let Some(template) = &args.template {
+        let re = compile_pattern(&pattern).map_err(CliError::Regex)?;
+        template
+            .validate(&re)
+            .map_err(|err| CliError::InvalidArgs(format!("Invalid template: {}", err)))?;
+    }
+
+    let no_match = match (args.default_prefix, args.fallback_dirname) {
+        (Some(prefix), _) => NoMatchPolicy::Prefix(prefix),
+        (None, true) => NoMatchPolicy::Dirname,
+        (None, false) => NoMatchPolicy::Refuse,
+    };
+    let options = RenameOptions {
+        pattern,
+        prefix: args.prefix,
+        reprefix: args.reprefix,
+        no_match,
+        separator: args.separator,
+        position: args.position,
+        ext_mode: args.ext_mode,
+        template: args.template,
+        ..args.select.into_options()?
+    };
+    Ok(paths
+        .into_iter()
+        .map(|(path, names)| {
+            let options = RenameOptions {
+                names,
+                ..options.clone()
+            };
+            (path, options)
+        })
+        .collect())
+}
+
+/// プレフィックスを付けます。
+fn add(args: AddArgs) -> Result<(), CliError> {
+    let (dry_run, run_args) = (args.select.dry_run, args.select.run);
+    let targets = add_targets(args)?;
+
+    // ファイルをリネーム
+    run(&targets, dry_run, run_args)
+}
+
+/// リネームを計画し、計画ファイルに書き出します。
+///
+/// 出力先を指定した場合は計画の内容と集計も表示します。
+fn plan(args: PlanArgs) -> Result<(), CliError> {
+    let format = args.add.select.run.format;
+    let mut targets = add_targets(args.add)?;
+    if targets.len() != 1 {
+        return Err(CliError::InvalidArgs(format!(
+            "A plan covers a single directory, but {} were given",
+            targets.len()
+        )));
+    }
+    let (path, options) = targets.remove(0);
+    let plan = match new_plan(&path, &options) {
+        Ok(plan) => plan,
+        Err(err) => return finish(Printer::new(format), Err(err)),
+    };
+
+    match &args.output {
+        Some(output) => File::create(output)
+            .and_then(|file| plan.write_to(BufWriter::new(file), args.plan_format)),
+        None => plan.write_to(io::stdout().lock(), args.plan_format),
+    }
+    .map_err(|err| CliError::Other {
+        context: "Error writing plan",
+        source: err.into(),
+    })?;
+    if let Some(output) = &args.output {
+        let mut printer = Printer::new(format);
+        let mut total = Summary::default();
+        printer.group(&path, false);
+        preview(&plan, &mut printer, &mut total);
+        printer.summary(&total);
+        printer.finish();
+        eprintln!("Plan written to {}", output.display());
     }
     Ok(())
 }
 
-fn main() {
-    // コマンドライン引数を解析
-    let args = Cli::parse();
+/// 計画ファイルのリネームを実行します。
+///
+/// 計画作成後にリネーム元が変更された場合は何も変更しません。
+fn apply(path: &Path, run_args: RunArgs) -> Result<(), CliError> {
+    let plan = File::open(path)
+        .map_err(Error::from)
+        .and_then(RenamePlan::read_from)
+        .map_err(|source| CliError::Other {
+            context: "Error reading plan",
+            source,
+        })?;
+    let mut printer = Printer::new(run_args.format);
+    let mut total = Summary::default();
+    printer.group(plan.root(), false);
+    let result = execute(&plan, &mut printer, run_args.failure_policy(), &mut total);
+    printer.summary(&total);
+    finish(printer, result)
+}
 
+/// プレフィックスを取り除きます。
+fn strip(args: StripArgs) -> Result<(), CliError> {
     let path = Path::new(&args.path);
-    let pattern = &args.pattern;
-    let dry_run = args.dry_run;
+    check_target(path)?;
+    let strip_regex = args
+        .regex
+        .as_deref()
+        .map(Regex::new)
+        .transpose()
+        .map_err(CliError::Regex)?;
+    // 正規表現で取り除く場合はディレクトリ名と照合しない
+    let (pattern, prefix) = match (&args.pattern, &strip_regex) {
+        (_, Some(re)) => (re.as_str().to_string(), Some(String::new())),
+        (pattern, None) => (pattern.clone().unwrap_or_default(), None),
+    };
 
-    // ディレクトリ名を取得
-    let dirname = match path.file_name() {
-        Some(name) => name.to_string_lossy().to_string(),
-        None => {
-            eprintln!("Invalid path");
-            return;
-        }
+    // ファイルをリネーム
+    let (dry_run, run_args) = (args.select.dry_run, args.select.run);
+    let options = RenameOptions {
+        pattern,
+        prefix,
+        separator: args.separator,
+        position: args.position,
+        ext_mode: args.ext_mode,
+        strip: true,
+        strip_regex,
+        ..args.select.into_options()?
     };
-    dbg!(&dirname);
+    run(&[(path.to_path_buf(), options)], dry_run, run_args)
+}
 
-    // プレフィックスを取得
-    let prefix = match get_prefix(pattern, &dirname) {
-        Ok(prefix) => prefix,
+/// ジャーナルに基づいて直前のリネームを取り消し、結果を表示します。
+fn undo(path: &Path, dry_run: bool) -> Result<(), CliError> {
+    let report = journal::undo(path, dry_run).map_err(|err| CliError::Other {
+        context: "Error undoing renames",
+        source: err.into(),
+    })?;
+    for (entry, status) in report.results.iter() {
+        let (new, old) = (escape_path(&entry.new), escape_path(&entry.old));
+        let mark = lossy_mark(&entry.new, &entry.old);
+        match status {
+            UndoStatus::Reverted | UndoStatus::Failed(_) => println!("{} -> {}{}", new, old, mark),
+            UndoStatus::Changed => {
+                eprintln!("Refusing to undo {}: changed since the rename{}", new, mark);
+            }
+            UndoStatus::Occupied => {
+                eprintln!("Refusing to undo {}: {} already exists{}", new, old, mark);
+            }
+        }
+    }
+    let (reverted, refused) = (report.reverted(), report.refused());
+    println!("{} reverted, {} refused", reverted, refused);
+
+    let failure = report
+        .results
+        .into_iter()
+        .find_map(|(_, status)| match status {
+            UndoStatus::Failed(err) => Some(err),
+            _ => None,
+        });
+    if let Some(source) = failure {
+        return Err(CliError::Undo {
+            partial: reverted > 0,
+            source,
+        });
+    }
+    if refused > 0 {
+        return Err(CliError::Refused { reverted, refused });
+    }
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    // コマンドライン引数を解析（不正な引数は clap が終了コード 2 で終了する）
+    let args = Cli::parse();
+
+    // ログを初期化（RUST_LOG でモジュールごとに上書き可能）
+    env_logger::Builder::new()
+        .filter_level(args.log_level())
+        .parse_default_env()
+        .init();
+
+    match try_main(args) {
+        Ok(()) => ExitCode::SUCCESS,
         Err(err) => {
-            eprintln!("Error compiling regex: {}", err);
-            return;
+            eprintln!("{}", err);
+            ExitCode::from(err.exit_code())
         }
-    };
-    dbg!(&prefix);
+    }
+}
 
-    // ファイルをリネーム
-    if let Err(err) = rename_files(path, &prefix, dry_run) {
-        eprintln!("Error renaming files: {}", err);
+/// サブコマンドを実行します。
+///
+/// # Errors
+///
+/// コマンドが失敗した場合（終了コードはエラーの種類で決まる）
+fn try_main(args: Cli) -> Result<(), CliError> {
+    match args.command {
+        Some(Command::Undo { path, dry_run }) => undo(Path::new(&path), dry_run),
+        Some(Command::Strip(args)) => strip(*args),
+        Some(Command::Plan(args)) => plan(*args),
+        Some(Command::Apply { plan, run }) => apply(&plan, run),
+        None => add(args.add),
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use clap::CommandFactory;
+
+    #[test]
+    fn test_cli_definition() {
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn test_exit_codes() {
+        let io_error = || io::Error::other("failed");
+        let rename = |partial| CliError::Rename {
+            partial,
+            source: io_error().into(),
+        };
+        assert_eq!(CliError::InvalidArgs(String::new()).exit_code(), 2);
+        assert_eq!(CliError::NoMatch(PathBuf::from("a")).exit_code(), 4);
+        assert_eq!(rename(true).exit_code(), 5);
+        assert_eq!(rename(false).exit_code(), 6);
+        let invalid = CliError::Rename {
+            partial: false,
+            source: Error::InvalidNames(Vec::new()),
+        };
+        assert_eq!(invalid.exit_code(), 2);
+        let refused = |reverted| CliError::Refused {
+            reverted,
+            refused: 1,
+        };
+        assert_eq!(refused(1).exit_code(), 5);
+        assert_eq!(refused(0).exit_code(), 6);
+    }
+
+    #[test]
+    fn test_separator_rejects_path_separators() {
+        assert!(Cli::try_parse_from(["prefix", "--separator", "-", "a"]).is_ok());
+        assert!(Cli::try_parse_from(["prefix", "--separator", "", "a"]).is_ok());
+        assert!(Cli::try_parse_from(["prefix", "--separator", "/", "a"]).is_err());
+        assert!(Cli::try_parse_from(["prefix", "strip", "--separator", "_/", "a"]).is_err());
+    }
+
+    #[test]
+    fn test_literal_prefix() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("20241231_shoot");
+        fs::create_dir(&target).unwrap();
+        let target_arg = target.to_str().unwrap();
+
+        let parse = |args: &[&str]| Cli::try_parse_from([&["prefix"], args].concat());
+        let cli = parse(&["--prefix", "PRJ042", target_arg]).unwrap();
+        let targets = add_targets(cli.add).unwrap();
+        assert_eq!(targets.len(), 1);
+        assert_eq!(targets[0].1.prefix.as_deref(), Some("PRJ042"));
+
+        assert!(parse(&["--prefix", "PRJ042", "-e", r"\d+", target_arg]).is_err());
+        assert!(parse(&["--prefix", "PRJ042", "--preset", "date8", target_arg]).is_err());
+        for prefix in ["", "a/b", "../x"] {
+            assert!(parse(&["--prefix", prefix, target_arg]).is_err());
+            assert!(parse(&["-e", "x", "--default-prefix", prefix, target_arg]).is_err());
+        }
+    }
 
     #[test]
-    fn test_get_prefix() {
-        let pattern = r"\d+";
-        let dirname = "20241231_sample";
-        let prefix = get_prefix(pattern, dirname).unwrap();
-        assert_eq!(prefix, "20241231");
+    fn test_invalid_regex_options() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().to_str().unwrap();
+        for option in ["--include-regex", "--exclude-regex"] {
+            let cli = Cli::try_parse_from(["prefix", option, "(", target]).unwrap();
+            let err = add_targets(cli.add).unwrap_err();
+            assert_eq!(err.exit_code(), 3);
+        }
+        let cli = Cli::try_parse_from(["prefix", "strip", "--regex", "(", target]).unwrap();
+        let Some(Command::Strip(args)) = cli.command else {
+            panic!("expected the strip subcommand");
+        };
+        assert_eq!(strip(*args).unwrap_err().exit_code(), 3);
     }
 
     #[test]
-    fn test_get_prefix_long_path() {
-        let pattern = r"\d+";
-        let dirname = "sample/20241231_sample";
-        let prefix = get_prefix(pattern, dirname).unwrap();
-        assert_eq!(prefix, "20241231");
+    fn test_group_by_parent() {
+        let dir = tempfile::tempdir().unwrap();
+        let shoot = dir.path().join("20240101_a");
+        fs::create_dir(&shoot).unwrap();
+        for name in ["x.txt", "y.txt"] {
+            fs::write(shoot.join(name), "").unwrap();
+        }
+        fs::write(dir.path().join("z.txt"), "").unwrap();
+
+        let input = [
+            shoot.join("x.txt"),
+            shoot.join("missing.txt"),
+            shoot.join(".").join("y.txt"),
+            dir.path().join("z.txt"),
+        ]
+        .map(|path| path.to_str().unwrap().to_string())
+        .join("\0");
+        let targets = group_by_parent(input.as_bytes(), b'\0').unwrap();
+        let root = fs::canonicalize(dir.path()).unwrap();
+        let names = |names: &[&str]| names.iter().map(OsString::from).collect::<HashSet<_>>();
+        assert_eq!(
+            targets,
+            [
+                (root.join("20240101_a"), names(&["x.txt", "y.txt"])),
+                (root.clone(), names(&["z.txt"])),
+            ]
+        );
+        assert!(group_by_parent(b".\n..\n", b'\n').is_err());
+        assert!(group_by_parent(b"", b'\n').is_err());
     }
 
     #[test]
-    fn test_get_prefix_empty_pattern() {
-        let pattern = "";
-        let dirname = "20241231_sample";
-        let prefix = get_prefix(pattern, dirname).unwrap();
-        assert_eq!(prefix, "20241231_sample");
+    fn test_stdin_find_output() {
+        let dir = tempfile::tempdir().unwrap();
+        let shoot = dir.path().join("shoot");
+        fs::create_dir(&shoot).unwrap();
+        for name in ["x.txt", "y.txt"] {
+            fs::write(shoot.join(name), "").unwrap();
+        }
+
+        // `find shoot -print0` と同じく、ディレクトリ自体を中身より先に出力する
+        let mut input = b".\0".to_vec();
+        for path in [shoot.clone(), shoot.join("x.txt"), shoot.join("y.txt")] {
+            input.extend_from_slice(path.to_str().unwrap().as_bytes());
+            input.push(b'\0');
+        }
+        let targets = group_by_parent(&input, b'\0')
+            .unwrap()
+            .into_iter()
+            .map(|(path, names)| {
+                let options = RenameOptions {
+                    prefix: Some("P".to_string()),
+                    names: Some(names),
+                    ..Default::default()
+                };
+                (path, options)
+            })
+            .collect::<Vec<_>>();
+        let run_args = RunArgs {
+            format: OutputFormat::Text,
+            keep_going: false,
+            atomic: false,
+        };
+        run(&targets, false, run_args).unwrap();
+        let renamed = dir.path().join("P_shoot");
+        assert!(renamed.join("P_x.txt").exists());
+        assert!(renamed.join("P_y.txt").exists());
     }
 
     #[test]
-    fn test_invalid_regex() {
-        let pattern = r"(\d+";
-        let dirname = "20241231_sample";
-        let result = get_prefix(pattern, dirname);
-        assert!(result.is_err());
+    fn test_expand_paths() {
+        let dir = tempfile::tempdir().unwrap();
+        for name in ["20240101_a", "20240202_b", "2023_c"] {
+            fs::create_dir(dir.path().join(name)).unwrap();
+        }
+        fs::write(dir.path().join("2024.txt"), "").unwrap();
+        let root = dir.path().to_str().unwrap();
+
+        let (a, b) = (dir.path().join("20240101_a"), dir.path().join("20240202_b"));
+        let paths = expand_paths(&[format!("{}/2024*", root)], false).unwrap();
+        assert_eq!(paths, [a.clone(), b.clone()]);
+        let missing = dir.path().join("missing_20241231");
+        let arg = missing.to_str().unwrap().to_string();
+        assert!(matches!(
+            expand_paths(&[arg], false),
+            Err(CliError::InvalidArgs(_))
+        ));
+        let file = format!("{}/2024.txt", root);
+        assert!(matches!(
+            expand_paths(&[file], false),
+            Err(CliError::InvalidArgs(_))
+        ));
+        assert!(expand_paths(&[format!("{}/2025*", root)], false).is_err());
+
+        // 重複は1つにまとめ、入れ子の対象パスは深いものから処理する
+        let args = [
+            root.to_string(),
+            format!("{}/20240101_a", root),
+            format!("{}/2024*", root),
+        ];
+        let paths = expand_paths(&args, false).unwrap();
+        assert_eq!(paths, [a, b, dir.path().to_path_buf()]);
+        assert!(expand_paths(&args, true).is_err());
     }
 }
@@ -0,0 +1,260 @@
+//! プレフィックスの挿入位置と拡張子の扱い
+
+use crate::os_name::{as_bytes, from_bytes};
+use crate::{ExtMode, Position, RenameOptions};
+use std::ffi::{OsStr, OsString};
+use std::path::{Path, PathBuf};
+
+/// 1つの拡張子とみなす複合拡張子
+const COMPOUND_EXTENSIONS: &[&str] = &[
+    ".tar.gz",
+    ".tar.bz2",
+    ".tar.xz",
+    ".tar.zst",
+    ".tar.lz",
+    ".tar.lzma",
+    ".tar.z",
+];
+
+/// ファイル名を拡張子の前後で分割します。
+///
+/// 先頭のドットは拡張子の区切りとみなしません（`.gitkeep` は拡張子なし）。
+///
+/// # Arguments
+///
+/// * `name` - ファイル名
+/// * `mode` - 拡張子とみなす範囲
+///
+/// # Returns
+///
+/// 拡張子を除いた名前と、ドットを含む拡張子（ない場合は空）
+pub(crate) fn split_ext(name: &OsStr, mode: ExtMode) -> (OsString, OsString) {
+    let (stem, ext) = split_ext_bytes(as_bytes(name), mode);
+    (from_bytes(stem.to_vec()), from_bytes(ext.to_vec()))
+}
+
+/// [`split_ext`] をバイト列に対して行います。
+fn split_ext_bytes(name: &[u8], mode: ExtMode) -> (&[u8], &[u8]) {
+    let start = usize::from(name.starts_with(b"."));
+    let pos = match mode {
+        ExtMode::All => name[start..].iter().position(|&b| b == b'.'),
+        ExtMode::Last => COMPOUND_EXTENSIONS
+            .iter()
+            .filter(|ext| name.len() > ext.len() + start)
+            .find(|ext| name[name.len() - ext.len()..].eq_ignore_ascii_case(ext.as_bytes()))
+            .map(|ext| name.len() - ext.len() - start)
+            .or_else(|| name[start..].iter().rposition(|&b| b == b'.')),
+    };
+    match pos {
+        Some(pos) => name.split_at(start + pos),
+        None => (name, b""),
+    }
+}
+
+/// プレフィックスを挿入する位置で名前を分割します。
+///
+/// # Returns
+///
+/// プレフィックスを挿入する位置より前の部分と後の部分
+fn split_at_position<'a>(name: &'a [u8], options: &RenameOptions) -> (&'a [u8], &'a [u8]) {
+    match options.position {
+        Position::Prefix => (b"", name),
+        Position::Suffix | Position::BeforeExt => split_ext_bytes(name, options.ext_mode),
+    }
+}
+
+/// 名前にプレフィックスを挿入します。
+///
+/// # Arguments
+///
+/// * `name` - ファイル名
+/// * `prefix` - プレフィックス
+/// * `options` - リネームオプション（区切り文字と挿入位置）
+///
+/// # Returns
+///
+/// リネーム後のファイル名
+pub(crate) fn insert_prefix(name: &OsStr, prefix: &str, options: &RenameOptions) -> OsString {
+    let (head, tail) = split_at_position(as_bytes(name), options);
+    let separator = options.separator.as_bytes();
+    from_bytes(match options.position {
+        Position::Prefix => [prefix.as_bytes(), separator, tail].concat(),
+        Position::Suffix | Position::BeforeExt => {
+            [head, separator, prefix.as_bytes(), tail].concat()
+        }
+    })
+}
+
+/// ファイル名に既にプレフィックスが付いているかを判定します。
+///
+/// # Arguments
+///
+/// * `name` - ファイル名
+/// * `prefix` - プレフィックス
+/// * `options` - リネームオプション（区切り文字と挿入位置）
+///
+/// # Returns
+///
+/// 挿入位置にプレフィックスと区切り文字が既にある場合は `true`
+pub(crate) fn has_prefix(name: &OsStr, prefix: &str, options: &RenameOptions) -> bool {
+    remove_prefix(name, prefix, options).is_some()
+}
+
+/// 名前からプレフィックスと区切り文字を取り除きます。
+///
+/// # Arguments
+///
+/// * `name` - ファイル名
+/// * `prefix` - プレフィックス
+/// * `options` - リネームオプション（区切り文字と挿入位置）
+///
+/// # Returns
+///
+/// 挿入位置にプレフィックスと区切り文字がある場合は取り除いた名前、ない場合は `None`
+pub(crate) fn remove_prefix(
+    name: &OsStr,
+    prefix: &str,
+    options: &RenameOptions,
+) -> Option<OsString> {
+    let (head, tail) = split_at_position(as_bytes(name), options);
+    let separator = options.separator.as_bytes();
+    let rest = match options.position {
+        Position::Prefix => tail
+            .strip_prefix(prefix.as_bytes())?
+            .strip_prefix(separator)?
+            .to_vec(),
+        Position::Suffix | Position::BeforeExt => {
+            let stem = head
+                .strip_suffix(prefix.as_bytes())?
+                .strip_suffix(separator)?;
+            [stem, tail].concat()
+        }
+    };
+    Some(from_bytes(rest))
+}
+
+/// リネーム後の名前がディレクトリ内のファイル名としてそのまま使えるかを判定します。
+///
+/// # Returns
+///
+/// 空でなく、`.` と `..` のどちらでもなく、パス区切り文字と NUL 文字を含まない場合は `true`
+pub(crate) fn is_plain_name(name: &OsStr) -> bool {
+    let bytes = as_bytes(name);
+    !bytes.is_empty()
+        && bytes != b"."
+        && bytes != b".."
+        && !bytes
+            .iter()
+            .any(|&b| b == 0 || std::path::is_separator(char::from(b)))
+}
+
+/// 名前の拡張子の前に連番を付けたパスを返します。
+///
+/// `.tar.gz` のような複合拡張子は1つの拡張子とみなし、その前に連番を付けます。
+pub(crate) fn with_counter(dest: &Path, counter: usize) -> PathBuf {
+    let (mut name, ext) = split_ext(dest.file_name().unwrap_or_default(), ExtMode::Last);
+    name.push(format!("_{}", counter));
+    name.push(ext);
+    dest.with_file_name(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_ext() {
+        let split_ext = |name: &'static str, mode| {
+            let (stem, ext) = split_ext_bytes(name.as_bytes(), mode);
+            (
+                std::str::from_utf8(stem).unwrap(),
+                std::str::from_utf8(ext).unwrap(),
+            )
+        };
+        assert_eq!(split_ext("test.txt", ExtMode::Last), ("test", ".txt"));
+        assert_eq!(split_ext("a.tar.gz", ExtMode::Last), ("a", ".tar.gz"));
+        assert_eq!(split_ext("a.b.c", ExtMode::Last), ("a.b", ".c"));
+        assert_eq!(split_ext("a.b.c", ExtMode::All), ("a", ".b.c"));
+        assert_eq!(split_ext(".gitkeep", ExtMode::All), (".gitkeep", ""));
+        assert_eq!(split_ext(".tar.gz", ExtMode::Last), (".tar", ".gz"));
+        assert_eq!(split_ext("README", ExtMode::Last), ("README", ""));
+    }
+
+    #[test]
+    fn test_insert_prefix_positions() {
+        let insert_prefix =
+            |name, prefix, options| insert_prefix(OsStr::new(name), prefix, options);
+        let has_prefix = |name, prefix, options| has_prefix(OsStr::new(name), prefix, options);
+        let at = |position, ext_mode| RenameOptions {
+            position,
+            ext_mode,
+            ..Default::default()
+        };
+        let suffix = at(Position::Suffix, ExtMode::Last);
+        assert_eq!(
+            insert_prefix("test.txt", "20241231", &suffix),
+            "test_20241231.txt"
+        );
+        assert_eq!(
+            insert_prefix("a.tar.gz", "20241231", &suffix),
+            "a_20241231.tar.gz"
+        );
+        let suffix_all = at(Position::Suffix, ExtMode::All);
+        assert_eq!(
+            insert_prefix("a.b.c", "20241231", &suffix_all),
+            "a_20241231.b.c"
+        );
+        let before_ext = at(Position::BeforeExt, ExtMode::Last);
+        assert_eq!(
+            insert_prefix("a.tar.gz", "20241231", &before_ext),
+            "a_20241231.tar.gz"
+        );
+        let before_all = at(Position::BeforeExt, ExtMode::All);
+        assert_eq!(
+            insert_prefix("a.b.c", "20241231", &before_all),
+            "a_20241231.b.c"
+        );
+
+        assert!(has_prefix("test_20241231.txt", "20241231", &suffix));
+        assert!(has_prefix("a_20241231.tar.gz", "20241231", &before_ext));
+        assert!(!has_prefix("20241231_test.txt", "20241231", &suffix));
+    }
+
+    #[test]
+    fn test_is_plain_name() {
+        assert!(is_plain_name(OsStr::new("20241231_a.txt")));
+        assert!(is_plain_name(OsStr::new("..a")));
+        for name in ["", ".", "..", "../a.txt", "a/b.txt", "a\0b"] {
+            assert!(!is_plain_name(OsStr::new(name)), "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn test_with_counter() {
+        assert_eq!(
+            with_counter(Path::new("sub/20241231_a.txt"), 1),
+            Path::new("sub/20241231_a_1.txt")
+        );
+        assert_eq!(
+            with_counter(Path::new("20241231_a.tar.gz"), 2),
+            Path::new("20241231_a_2.tar.gz")
+        );
+        assert_eq!(
+            with_counter(Path::new(".gitkeep"), 1),
+            Path::new(".gitkeep_1")
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_non_utf8_name() {
+        let name = from_bytes(b"caf\xe9.tar.gz".to_vec());
+        let options = RenameOptions {
+            position: Position::BeforeExt,
+            ..Default::default()
+        };
+        let renamed = insert_prefix(&name, "20241231", &options);
+        assert_eq!(as_bytes(&renamed), b"caf\xe9_20241231.tar.gz");
+        assert_eq!(remove_prefix(&renamed, "20241231", &options), Some(name));
+    }
+}
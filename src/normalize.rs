@@ -0,0 +1,90 @@
+//! ファイル名とディレクトリ名の Unicode 正規化
+
+use crate::os_name::escape_path;
+use crate::Normalization;
+use log::warn;
+use std::borrow::Cow;
+use std::ffi::{OsStr, OsString};
+use std::path::Path;
+use unicode_normalization::{is_nfc, is_nfd, UnicodeNormalization};
+
+impl Normalization {
+    /// 文字列を正規化します。既に正規化されている場合はそのまま返します。
+    pub fn apply<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        match self {
+            Normalization::None => Cow::Borrowed(text),
+            Normalization::Nfc if is_nfc(text) => Cow::Borrowed(text),
+            Normalization::Nfd if is_nfd(text) => Cow::Borrowed(text),
+            Normalization::Nfc => Cow::Owned(text.nfc().collect()),
+            Normalization::Nfd => Cow::Owned(text.nfd().collect()),
+            Normalization::Nfkc => Cow::Owned(text.nfkc().collect()),
+        }
+    }
+
+    /// 名前を正規化します。UTF-8 でない名前はそのまま返します。
+    pub(crate) fn apply_os<'a>(&self, name: &'a OsStr) -> Cow<'a, OsStr> {
+        match name.to_str().map(|text| self.apply(text)) {
+            Some(Cow::Owned(text)) => Cow::Owned(OsString::from(text)),
+            _ => Cow::Borrowed(name),
+        }
+    }
+}
+
+/// 名前に合成済みの文字と分解された文字が混在しているかを判定します。
+///
+/// NFC と NFD のどちらでもない名前を混在とみなします。
+pub fn is_mixed(text: &str) -> bool {
+    !is_nfc(text) && !is_nfd(text)
+}
+
+/// 名前を `form` と同じ正規化形式にそろえます。
+///
+/// `form` が NFC と NFD のどちらか一方だけを満たす場合に変換し、
+/// それ以外の場合や UTF-8 でない場合はそのまま返します。
+pub(crate) fn match_form<'a>(name: &'a OsStr, form: &OsStr) -> Cow<'a, OsStr> {
+    let Some(form) = form.to_str() else {
+        return Cow::Borrowed(name);
+    };
+    match (is_nfc(form), is_nfd(form)) {
+        (true, false) => Normalization::Nfc.apply_os(name),
+        (false, true) => Normalization::Nfd.apply_os(name),
+        _ => Cow::Borrowed(name),
+    }
+}
+
+/// 名前に正規化形式が混在している場合に警告します。
+pub(crate) fn warn_if_mixed(path: &Path) {
+    let name = path.file_name().and_then(OsStr::to_str).unwrap_or_default();
+    if is_mixed(name) {
+        warn!(
+            "{} mixes composed and decomposed characters, consider --normalize",
+            escape_path(path)
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize() {
+        let nfd = "\u{30d5}\u{309a}\u{30ed}\u{30b7}\u{3099}\u{30a7}\u{30af}\u{30c8}";
+        let nfc = "\u{30d7}\u{30ed}\u{30b8}\u{30a7}\u{30af}\u{30c8}";
+        assert_eq!(Normalization::Nfc.apply(nfd), nfc);
+        assert_eq!(Normalization::Nfd.apply(nfc), nfd);
+        assert_eq!(Normalization::None.apply(nfd), nfd);
+        assert_eq!(Normalization::Nfkc.apply("ﾌﾟﾛｼﾞｪｸﾄ"), nfc);
+
+        assert!(!is_mixed(nfc));
+        assert!(!is_mixed(nfd));
+        assert!(is_mixed("\u{30d7}\u{30ed}\u{30b7}\u{3099}"));
+
+        let prefixed = OsStr::new("\u{30d7}_\u{30d5}\u{309a}.txt");
+        assert_eq!(
+            match_form(prefixed, OsStr::new(nfd)),
+            OsStr::new("\u{30d5}\u{309a}_\u{30d5}\u{309a}.txt")
+        );
+        assert_eq!(match_form(prefixed, OsStr::new("a.txt")), prefixed);
+    }
+}
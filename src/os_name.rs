@@ -0,0 +1,106 @@
+//! UTF-8 でないファイル名を失わずに扱うための変換
+//!
+//! 名前はバイト列（[`OsStr::as_encoded_bytes`]）のまま加工し、表示するときだけ
+//! UTF-8 でないバイトを `\xNN` に置き換えます。
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::borrow::Cow;
+use std::ffi::{OsStr, OsString};
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+
+/// 名前のバイト列を取得します。
+pub(crate) fn as_bytes(name: &OsStr) -> &[u8] {
+    name.as_encoded_bytes()
+}
+
+/// [`as_bytes`] で取得したバイト列を ASCII 文字の位置で分割・連結したものや、
+/// 標準入力から読み込んだバイト列を名前に戻します。
+///
+/// Unix 以外では UTF-8 でないバイトを置換文字に変換します。
+#[cfg(unix)]
+pub fn from_bytes(bytes: Vec<u8>) -> OsString {
+    use std::os::unix::ffi::OsStringExt;
+    OsString::from_vec(bytes)
+}
+
+/// [`as_bytes`] で取得したバイト列を ASCII 文字の位置で分割・連結したものや、
+/// 標準入力から読み込んだバイト列を名前に戻します。
+///
+/// Unix 以外では UTF-8 でないバイトを置換文字に変換します。
+#[cfg(not(unix))]
+pub fn from_bytes(bytes: Vec<u8>) -> OsString {
+    String::from_utf8_lossy(&bytes).into_owned().into()
+}
+
+/// 表示用にパスを文字列に変換します。
+///
+/// UTF-8 でないバイトは `\xNN` で表すため、元の名前には戻せません。
+/// 変換が必要だったかは `path.to_str().is_none()` で判定できます。
+pub fn escape_path(path: &Path) -> Cow<'_, str> {
+    if let Some(text) = path.to_str() {
+        return Cow::Borrowed(text);
+    }
+    let mut out = String::new();
+    for chunk in path.as_os_str().as_encoded_bytes().utf8_chunks() {
+        out.push_str(chunk.valid());
+        for byte in chunk.invalid() {
+            let _ = write!(out, "\\x{:02X}", byte);
+        }
+    }
+    Cow::Owned(out)
+}
+
+/// ジャーナルや計画ファイルでのパスの表現
+///
+/// UTF-8 の名前は文字列、それ以外は `{"bytes": [...]}` として記録します。
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum Repr {
+    Text(String),
+    Bytes { bytes: Vec<u8> },
+}
+
+/// パスを UTF-8 でない名前も含めて失わずに直列化します（`#[serde(with)]` 用）。
+pub(crate) fn serialize<S: Serializer>(path: &Path, serializer: S) -> Result<S::Ok, S::Error> {
+    match path.to_str() {
+        Some(text) => Repr::Text(text.to_string()),
+        None => Repr::Bytes {
+            bytes: as_bytes(path.as_os_str()).to_vec(),
+        },
+    }
+    .serialize(serializer)
+}
+
+/// [`serialize`] で直列化したパスを読み込みます（`#[serde(with)]` 用）。
+pub(crate) fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<PathBuf, D::Error> {
+    Ok(match Repr::deserialize(deserializer)? {
+        Repr::Text(text) => PathBuf::from(text),
+        Repr::Bytes { bytes } => PathBuf::from(from_bytes(bytes)),
+    })
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[derive(Serialize, Deserialize)]
+    struct Record {
+        #[serde(with = "super")]
+        path: PathBuf,
+    }
+
+    #[test]
+    fn test_non_utf8_round_trip() {
+        let path = PathBuf::from(from_bytes(b"caf\xe9.txt".to_vec()));
+        assert_eq!(escape_path(&path), "caf\\xE9.txt");
+
+        let json = serde_json::to_string(&Record { path: path.clone() }).unwrap();
+        assert_eq!(json, r#"{"path":{"bytes":[99,97,102,233,46,116,120,116]}}"#);
+        let record: Record = serde_json::from_str(&json).unwrap();
+        assert_eq!(record.path, path);
+
+        let record: Record = serde_json::from_str(r#"{"path":"a.txt"}"#).unwrap();
+        assert_eq!(record.path, Path::new("a.txt"));
+    }
+}
@@ -0,0 +1,325 @@
+//! リネーム結果の表示（テキストと構造化形式）
+
+use clap::ValueEnum;
+use prefix::{escape_path, Action, Operation, Summary};
+use serde::Serialize;
+use std::fmt::Display;
+use std::path::Path;
+
+/// 実行結果の出力形式
+#[derive(Clone, Copy, Debug, Default, PartialEq, ValueEnum)]
+pub enum OutputFormat {
+    /// 人が読むためのテキスト
+    #[default]
+    Text,
+    /// すべてのエントリと集計をまとめた1つの JSON
+    Json,
+    /// 1行に1レコードの JSON（最後に集計）
+    Jsonl,
+    /// 見出し付きの CSV（最後の行が集計）
+    Csv,
+}
+
+/// 1エントリ分の出力レコード
+#[derive(Serialize)]
+struct Entry {
+    /// 対象パス
+    directory: String,
+    /// リネーム元
+    source: String,
+    /// リネーム先
+    destination: String,
+    /// 操作の種類（rename, overwrite, skip, rollback）
+    action: &'static str,
+    /// スキップした理由
+    reason: Option<&'static str>,
+    /// 失敗した理由
+    error: Option<String>,
+    /// UTF-8 でない名前を `\xNN` で表したか（元の名前には戻せない）
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    lossy: bool,
+}
+
+impl Entry {
+    /// リネーム元とリネーム先から表示用のエントリを作成します。
+    fn new(source: &Path, destination: &Path, action: &'static str) -> Self {
+        Entry {
+            directory: String::new(),
+            source: escape_path(source).into_owned(),
+            destination: escape_path(destination).into_owned(),
+            action,
+            reason: None,
+            error: None,
+            lossy: is_lossy(source, destination),
+        }
+    }
+}
+
+/// 集計の出力レコード
+#[derive(Serialize)]
+struct SummaryRecord {
+    renamed: usize,
+    skipped: usize,
+    failed: usize,
+    rolled_back: usize,
+}
+
+/// JSON Lines の1行分のレコード
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum Record<'a> {
+    Entry(&'a Entry),
+    Summary(&'a SummaryRecord),
+}
+
+/// JSON 形式でまとめて出力する内容
+#[derive(Serialize)]
+struct Document {
+    entries: Vec<Entry>,
+    summary: Option<SummaryRecord>,
+}
+
+/// CSV の見出し行
+const CSV_HEADER: &str = "type,directory,source,destination,action,reason,error,lossy,\
+    renamed,skipped,failed,rolled_back";
+
+/// テキスト形式で UTF-8 でない名前を含む行に付ける印
+const LOSSY_MARK: &str = " [non-UTF-8 name shown with \\xNN escapes]";
+
+/// 操作と集計を指定された形式で標準出力に表示します。
+///
+/// JSON 形式は [`Printer::finish`] でまとめて出力し、それ以外は1件ずつ出力します。
+/// テキスト形式ではリネームに失敗した操作を最後に標準エラー出力へまとめて表示します。
+pub struct Printer {
+    format: OutputFormat,
+    /// 現在の対象パス（構造化形式のエントリに含める）
+    directory: String,
+    /// テキスト形式で見出しを表示した対象パスの数
+    headers: usize,
+    entries: Vec<Entry>,
+    summary: Option<SummaryRecord>,
+    failures: Vec<String>,
+}
+
+impl Printer {
+    /// 出力を開始します。CSV 形式では見出し行を表示します。
+    pub fn new(format: OutputFormat) -> Self {
+        if format == OutputFormat::Csv {
+            println!("{}", CSV_HEADER);
+        }
+        Printer {
+            format,
+            directory: String::new(),
+            headers: 0,
+            entries: Vec::new(),
+            summary: None,
+            failures: Vec::new(),
+        }
+    }
+
+    /// 以降の操作の対象パスを設定します。
+    ///
+    /// # Arguments
+    ///
+    /// * `root` - 対象パス
+    /// * `header` - テキスト形式で対象パスの見出しを表示するか（複数の対象パスを処理する場合）
+    pub fn group(&mut self, root: &Path, header: bool) {
+        self.directory = escape_path(root).into_owned();
+        if header && self.format == OutputFormat::Text {
+            if self.headers > 0 {
+                println!();
+            }
+            println!("{}:{}", self.directory, lossy_mark(root, root));
+            self.headers += 1;
+        }
+    }
+
+    /// 操作を1件表示します。
+    ///
+    /// # Arguments
+    ///
+    /// * `op` - 操作
+    /// * `error` - リネームに失敗した場合のエラー（テキスト形式では最後にまとめて表示）
+    pub fn operation(&mut self, op: &Operation, error: Option<&dyn Display>) {
+        if self.format == OutputFormat::Text {
+            let (src, dest) = (escape_path(&op.src), escape_path(&op.dest));
+            let mark = lossy_mark(&op.src, &op.dest);
+            match op.action {
+                Action::Rename => println!("{} -> {}{}", src, dest, mark),
+                Action::Overwrite => println!("{} -> {} (overwrite){}", src, dest, mark),
+                Action::Skip(reason) => {
+                    println!(
+                        "{} (skipped: {}){}",
+                        src,
+                        reason,
+                        lossy_mark(&op.src, &op.src)
+                    )
+                }
+            }
+            if let Some(err) = error {
+                self.failures
+                    .push(format!("{} -> {}: {}{}", src, dest, err, mark));
+            }
+            return;
+        }
+
+        self.entry(Entry {
+            reason: match op.action {
+                Action::Skip(reason) => Some(reason.id()),
+                Action::Rename | Action::Overwrite => None,
+            },
+            error: error.map(|err| err.to_string()),
+            ..Entry::new(&op.src, &op.dest, op.action.id())
+        });
+    }
+
+    /// ロールバックした操作を1件表示します。リネーム先から元の名前へ戻した記録になります。
+    ///
+    /// # Arguments
+    ///
+    /// * `op` - 元に戻した操作
+    /// * `error` - 元に戻せなかった場合のエラー（テキスト形式では最後にまとめて表示）
+    pub fn rollback(&mut self, op: &Operation, error: Option<&dyn Display>) {
+        if self.format == OutputFormat::Text {
+            let (dest, src) = (escape_path(&op.dest), escape_path(&op.src));
+            let mark = lossy_mark(&op.dest, &op.src);
+            match error {
+                None => println!("{} -> {} (rolled back){}", dest, src, mark),
+                Some(err) => self
+                    .failures
+                    .push(format!("rollback {} -> {}: {}{}", dest, src, err, mark)),
+            }
+            return;
+        }
+
+        self.entry(Entry {
+            error: error.map(|err| err.to_string()),
+            ..Entry::new(&op.dest, &op.src, "rollback")
+        });
+    }
+
+    /// 構造化形式のエントリを出力します（JSON 形式では最後にまとめて出力）。
+    fn entry(&mut self, entry: Entry) {
+        let entry = Entry {
+            directory: self.directory.clone(),
+            ..entry
+        };
+        match self.format {
+            OutputFormat::Jsonl => println!("{}", json(&Record::Entry(&entry))),
+            OutputFormat::Csv => println!(
+                "entry,{},{},{},{},{},{},{},,,,",
+                csv(&entry.directory),
+                csv(&entry.source),
+                csv(&entry.destination),
+                entry.action,
+                entry.reason.unwrap_or_default(),
+                csv(entry.error.as_deref().unwrap_or_default()),
+                if entry.lossy { "true" } else { "" }
+            ),
+            _ => self.entries.push(entry),
+        }
+    }
+
+    /// 計画の段階で拒否された操作を表示します。
+    ///
+    /// テキスト形式では `label` を付けて標準エラー出力に表示します。
+    pub fn rejected(&mut self, op: &Operation, label: &str, error: &str) {
+        if self.format == OutputFormat::Text {
+            eprintln!(
+                "{}: {} -> {}{}",
+                label,
+                escape_path(&op.src),
+                escape_path(&op.dest),
+                lossy_mark(&op.src, &op.dest)
+            );
+        } else {
+            self.operation(op, Some(&error));
+        }
+    }
+
+    /// 集計を表示します。
+    pub fn summary(&mut self, summary: &Summary) {
+        let record = SummaryRecord {
+            renamed: summary.renamed,
+            skipped: summary.skipped,
+            failed: summary.failed,
+            rolled_back: summary.rolled_back,
+        };
+        match self.format {
+            OutputFormat::Text => {
+                let mut line = format!("{} renamed, {} skipped", record.renamed, record.skipped);
+                if record.failed > 0 {
+                    line.push_str(&format!(", {} failed", record.failed));
+                }
+                if record.rolled_back > 0 {
+                    line.push_str(&format!(", {} rolled back", record.rolled_back));
+                }
+                println!("{}", line);
+            }
+            OutputFormat::Jsonl => println!("{}", json(&Record::Summary(&record))),
+            OutputFormat::Csv => println!(
+                "summary,,,,,,,,{},{},{},{}",
+                record.renamed, record.skipped, record.failed, record.rolled_back
+            ),
+            OutputFormat::Json => self.summary = Some(record),
+        }
+    }
+
+    /// まとめて出力する形式の内容と、失敗した操作の一覧を表示します。
+    pub fn finish(self) {
+        if !self.failures.is_empty() {
+            eprintln!("Failed renames:");
+            for failure in &self.failures {
+                eprintln!("  {}", failure);
+            }
+        }
+        if self.format == OutputFormat::Json {
+            let document = Document {
+                entries: self.entries,
+                summary: self.summary,
+            };
+            println!("{}", json(&document));
+        }
+    }
+}
+
+/// どちらかのパスが UTF-8 でなく、表示がエスケープされるかを判定します。
+pub fn is_lossy(a: &Path, b: &Path) -> bool {
+    a.to_str().is_none() || b.to_str().is_none()
+}
+
+/// テキスト形式の行に付ける、UTF-8 でない名前を含むことを示す印を返します。
+pub fn lossy_mark(a: &Path, b: &Path) -> &'static str {
+    if is_lossy(a, b) {
+        LOSSY_MARK
+    } else {
+        ""
+    }
+}
+
+/// 値を JSON 文字列に変換します。
+fn json(value: &impl Serialize) -> String {
+    // 文字列と数値のみからなるため失敗しない
+    serde_json::to_string(value).unwrap_or_default()
+}
+
+/// CSV の1フィールドとして必要に応じて引用符で囲みます。
+fn csv(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_csv_quoting() {
+        assert_eq!(csv("a.txt"), "a.txt");
+        assert_eq!(csv("a,b.txt"), "\"a,b.txt\"");
+        assert_eq!(csv("say \"hi\".txt"), "\"say \"\"hi\"\".txt\"");
+    }
+}
@@ -0,0 +1,1515 @@
+//! リネームの計画と実行
+
+use crate::journal::{self, JournalRun, JOURNAL_FILE_NAME};
+use crate::naming::{
+    has_prefix, insert_prefix, is_plain_name, remove_prefix, split_ext, with_counter,
+};
+use crate::normalize::{match_form, warn_if_mixed};
+use crate::os_name::escape_path;
+use crate::template::DirMatch;
+use crate::{
+    compile_pattern, ConflictPolicy, EntryType, Error, FailurePolicy, NoMatchPolicy, PrefixFrom,
+    RenameOptions,
+};
+use log::{debug, info, trace, warn};
+use regex::Regex;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsStr;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::ops::AddAssign;
+use std::path::{Path, PathBuf};
+
+/// リネームをスキップする理由
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SkipReason {
+    /// 既にプレフィックスが付いている
+    AlreadyPrefixed,
+    /// 取り除くプレフィックスが付いていない
+    NotPrefixed,
+    /// リネーム先が衝突した
+    Conflict,
+    /// パターンがディレクトリ名に一致せず、プレフィックスがない
+    NoMatch,
+    /// 名前を指定された文字コードとして解釈できない
+    Undecodable,
+}
+
+impl SkipReason {
+    /// 計画ファイルや構造化出力に使う識別子を返します。
+    pub fn id(&self) -> &'static str {
+        match self {
+            SkipReason::AlreadyPrefixed => "already-prefixed",
+            SkipReason::NotPrefixed => "not-prefixed",
+            SkipReason::Conflict => "conflict",
+            SkipReason::NoMatch => "no-match",
+            SkipReason::Undecodable => "undecodable",
+        }
+    }
+}
+
+impl fmt::Display for SkipReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            SkipReason::AlreadyPrefixed => "already prefixed",
+            SkipReason::NotPrefixed => "not prefixed",
+            SkipReason::Conflict => "conflict",
+            SkipReason::NoMatch => "pattern did not match the directory name",
+            SkipReason::Undecodable => "name cannot be decoded",
+        })
+    }
+}
+
+/// 計画したリネーム操作の種類
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Action {
+    /// リネームする
+    Rename,
+    /// 既存のファイルを上書きしてリネームする
+    Overwrite,
+    /// スキップする
+    Skip(SkipReason),
+}
+
+impl Action {
+    /// 計画ファイルや構造化出力に使う識別子を返します。
+    pub fn id(&self) -> &'static str {
+        match self {
+            Action::Rename => "rename",
+            Action::Overwrite => "overwrite",
+            Action::Skip(_) => "skip",
+        }
+    }
+}
+
+/// 計画したリネーム操作
+#[derive(Clone, Debug, PartialEq)]
+pub struct Operation {
+    /// リネーム元（対象パスからの相対パス）
+    pub src: PathBuf,
+    /// リネーム先（対象パスからの相対パス）
+    pub dest: PathBuf,
+    /// 操作の種類
+    pub action: Action,
+}
+
+/// リネーム処理の集計結果
+#[derive(Debug, Default, PartialEq)]
+pub struct Summary {
+    /// リネームしたファイル数
+    pub renamed: usize,
+    /// 既にプレフィックスが付いている（取り除く場合は付いていない）、衝突した、
+    /// プレフィックスがない、または名前を変換できないためスキップしたファイル数
+    pub skipped: usize,
+    /// リネームに失敗したファイル数
+    pub failed: usize,
+    /// 失敗後にリネームを元に戻したファイル数（`renamed` には含めない）
+    pub rolled_back: usize,
+}
+
+impl AddAssign<&Summary> for Summary {
+    /// 別の対象パスの集計結果を加算します。
+    fn add_assign(&mut self, other: &Summary) {
+        self.renamed += other.renamed;
+        self.skipped += other.skipped;
+        self.failed += other.failed;
+        self.rolled_back += other.rolled_back;
+    }
+}
+
+/// 1件の操作の実行結果
+#[derive(Debug)]
+pub enum Status {
+    /// リネームした
+    Renamed,
+    /// スキップした
+    Skipped(SkipReason),
+    /// リネームに失敗した
+    Failed(io::Error),
+}
+
+/// 計画の実行結果
+#[derive(Debug, Default)]
+pub struct Report {
+    /// 実行した操作と結果（実行順）
+    pub results: Vec<(Operation, Status)>,
+    /// 集計結果
+    pub summary: Summary,
+    /// ロールバックした操作と結果（ロールバックした順）
+    pub rollback: Vec<(Operation, io::Result<()>)>,
+}
+
+impl Report {
+    /// 操作の結果を追加して集計します。
+    fn push(&mut self, op: Operation, status: Status) {
+        match status {
+            Status::Renamed => self.summary.renamed += 1,
+            Status::Skipped(_) => self.summary.skipped += 1,
+            Status::Failed(_) => self.summary.failed += 1,
+        }
+        self.results.push((op, status));
+    }
+}
+
+/// 対象パス内のリネーム計画
+///
+/// 作成時にすべてのリネーム先を決めて衝突を検出するため、実行前に内容を確認できます。
+#[derive(Debug)]
+pub struct RenamePlan {
+    /// 対象パス
+    root: PathBuf,
+    /// ジャーナルに記録する正規表現パターン
+    pattern: String,
+    /// ジャーナルに記録する区切り文字
+    separator: String,
+    /// 実行順のリネーム操作
+    operations: Vec<Operation>,
+    /// 計画作成時の各リネーム元のサイズと更新日時（操作と同じ順）
+    fingerprints: Vec<(u64, u64)>,
+}
+
+impl RenamePlan {
+    /// 指定されたパス内のファイルのリネームを計画します。
+    ///
+    /// 既存のファイルや他のリネーム先との衝突は `options.on_conflict` に従って処理します。
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - 対象パス
+    /// * `options` - リネームオプション
+    ///
+    /// # Errors
+    ///
+    /// 正規表現のコンパイルやディレクトリの読み込みに失敗した場合、
+    /// パターンが対象パスのディレクトリ名に一致せず `options.no_match` が拒否の場合、
+    /// または衝突時の動作が中止で衝突がある場合
+    pub fn new(path: impl AsRef<Path>, options: &RenameOptions) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let re = compile_pattern(&options.pattern)?;
+        warn_if_mixed(path);
+        let captured = capture_dirname(&re, path.file_name().unwrap_or_default(), options);
+        let top_match = DirMatch {
+            prefix: top_prefix(path, captured.prefix, options)?,
+            ..captured
+        };
+        debug!(
+            "planning {} with prefix {:?}",
+            path.display(),
+            top_match.prefix
+        );
+        let nearest = (options.prefix_from == PrefixFrom::Nearest).then_some(&re);
+        let mut targets = Vec::new();
+        collect_entries(
+            path,
+            Path::new(""),
+            0,
+            &top_match,
+            options,
+            nearest,
+            &mut targets,
+        )?;
+
+        let mut operations = Vec::new();
+        let mut invalid = Vec::new();
+        let mut counter = 0;
+        for (src, dir_match) in targets {
+            let Some(filename) = src.file_name() else {
+                continue;
+            };
+            let filename = match options.from_encoding {
+                None => Cow::Borrowed(filename),
+                Some(encoding) => match encoding.decode(filename) {
+                    Some(decoded) => decoded,
+                    None => {
+                        warn!(
+                            "{} cannot be decoded as {}, skipped",
+                            escape_path(&src),
+                            encoding
+                        );
+                        operations.push(Operation {
+                            dest: src.clone(),
+                            src,
+                            action: Action::Skip(SkipReason::Undecodable),
+                        });
+                        continue;
+                    }
+                },
+            };
+            warn_if_mixed(&src.with_file_name(&filename));
+            // プレフィックスの有無は正規化した名前でも確認し、リネーム後の名前は指定に従って作る
+            let normalized = options.normalize.apply_os(&filename).into_owned();
+            let src_name = if options.normalize_names {
+                normalized.clone()
+            } else {
+                filename.into_owned()
+            };
+            let dest_name = if options.strip {
+                // 正規表現は表示用の文字列で照合し、取り除くのは一致した部分とバイト列が同じ場合のみ
+                let lossy = src_name.to_string_lossy();
+                let prefix = match &options.strip_regex {
+                    Some(re) => re
+                        .find(&lossy)
+                        .filter(|m| m.start() == 0)
+                        .map_or("", |m| m.as_str()),
+                    None => &dir_match.prefix,
+                };
+                let stripped = remove_prefix(&src_name, prefix, options)
+                    .or_else(|| remove_prefix(&normalized, prefix, options))
+                    .filter(|rest| !prefix.is_empty() && !rest.is_empty());
+                let Some(dest_name) = stripped else {
+                    operations.push(Operation {
+                        dest: src.clone(),
+                        src,
+                        action: Action::Skip(SkipReason::NotPrefixed),
+                    });
+                    continue;
+                };
+                dest_name
+            } else if dir_match.prefix.is_empty() {
+                // 空のプレフィックスで `_test.txt` のような名前にしない
+                operations.push(Operation {
+                    dest: src.clone(),
+                    src,
+                    action: Action::Skip(SkipReason::NoMatch),
+                });
+                continue;
+            } else {
+                let (dest_name, already) = match &options.template {
+                    Some(template) => {
+                        counter += 1;
+                        let (stem, ext) = split_ext(&src_name, options.ext_mode);
+                        (
+                            template.render(&dir_match, &stem, &ext, counter),
+                            template.matches(&dir_match, &src_name)
+                                || template.matches(&dir_match, &normalized),
+                        )
+                    }
+                    None => (
+                        insert_prefix(&src_name, &dir_match.prefix, options),
+                        has_prefix(&src_name, &dir_match.prefix, options)
+                            || has_prefix(&normalized, &dir_match.prefix, options),
+                    ),
+                };
+                // 正規化した名前を使わない場合は、挿入したプレフィックスを元の名前の正規化形式にそろえる
+                let dest_name = if options.normalize_names {
+                    dest_name
+                } else {
+                    match_form(&dest_name, &src_name).into_owned()
+                };
+                if !options.reprefix && already {
+                    operations.push(Operation {
+                        dest: src.with_file_name(dest_name),
+                        src,
+                        action: Action::Skip(SkipReason::AlreadyPrefixed),
+                    });
+                    continue;
+                }
+                dest_name
+            };
+            // テンプレートや区切り文字によって別のディレクトリへ移動しない
+            if !is_plain_name(&dest_name) {
+                invalid.push(Operation {
+                    dest: src.with_file_name(dest_name),
+                    src,
+                    action: Action::Rename,
+                });
+                continue;
+            }
+
+            // 衝突はすべてのリネーム先が決まってから解決する
+            operations.push(Operation {
+                dest: src.with_file_name(dest_name),
+                src,
+                action: Action::Rename,
+            });
+        }
+
+        if !invalid.is_empty() {
+            return Err(Error::InvalidNames(invalid));
+        }
+        let (operations, conflicts) = Resolver::new(path, options.on_conflict, operations).run();
+        if !conflicts.is_empty() {
+            return Err(Error::Conflicts(conflicts));
+        }
+        let fingerprints = operations
+            .iter()
+            .map(|op| journal::fingerprint(&path.join(&op.src)))
+            .collect::<io::Result<_>>()?;
+        Ok(RenamePlan {
+            root: path.to_path_buf(),
+            pattern: options.pattern.clone(),
+            separator: options.separator.clone(),
+            operations,
+            fingerprints,
+        })
+    }
+
+    /// 保存した計画から復元します。
+    pub(crate) fn from_parts(
+        root: PathBuf,
+        pattern: String,
+        separator: String,
+        operations: Vec<(Operation, (u64, u64))>,
+    ) -> Self {
+        let (operations, fingerprints) = operations.into_iter().unzip();
+        RenamePlan {
+            root,
+            pattern,
+            separator,
+            operations,
+            fingerprints,
+        }
+    }
+
+    /// ジャーナルに記録する正規表現パターンを返します。
+    pub fn pattern(&self) -> &str {
+        &self.pattern
+    }
+
+    /// ジャーナルに記録する区切り文字を返します。
+    pub fn separator(&self) -> &str {
+        &self.separator
+    }
+
+    /// 操作と計画作成時のリネーム元のサイズと更新日時の組を返します。
+    pub(crate) fn fingerprinted(&self) -> impl Iterator<Item = (&Operation, (u64, u64))> {
+        self.operations
+            .iter()
+            .zip(self.fingerprints.iter().copied())
+    }
+
+    /// 計画作成後にリネーム元が変更または削除されていないか、
+    /// リネーム先が新たに使われていないかを確認します。
+    ///
+    /// # Errors
+    ///
+    /// 計画と一致しなくなった操作がある場合
+    pub fn verify(&self) -> Result<(), Error> {
+        // 先の操作でリネームされて空く名前はリネーム先として使える
+        let mut vacated = HashSet::new();
+        let mut stale = Vec::new();
+        for (op, fp) in self.fingerprinted() {
+            if matches!(op.action, Action::Skip(_)) {
+                continue;
+            }
+            let changed = journal::fingerprint(&self.root.join(&op.src))
+                .map_or(true, |current| current != fp);
+            let occupied = op.action == Action::Rename
+                && !vacated.contains(&op.dest)
+                && fs::symlink_metadata(self.root.join(&op.dest)).is_ok();
+            if changed || occupied {
+                stale.push(op.clone());
+            }
+            vacated.insert(&op.src);
+        }
+        if !stale.is_empty() {
+            return Err(Error::Stale(stale));
+        }
+        Ok(())
+    }
+
+    /// 対象パスを返します。
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// 実行順のリネーム操作を返します。
+    pub fn operations(&self) -> &[Operation] {
+        &self.operations
+    }
+
+    /// 計画どおりに実行した場合の集計結果を返します。
+    pub fn summary(&self) -> Summary {
+        let skipped = self
+            .operations
+            .iter()
+            .filter(|op| matches!(op.action, Action::Skip(_)))
+            .count();
+        Summary {
+            renamed: self.operations.len() - skipped,
+            skipped,
+            ..Default::default()
+        }
+    }
+
+    /// 計画したリネームを実行し、ジャーナルに記録します。
+    ///
+    /// リネームに失敗した場合はそこで中断し、それまでの結果を返します。
+    ///
+    /// # Errors
+    ///
+    /// 計画と一致しなくなった操作がある場合、またはジャーナルの書き込みに失敗した場合
+    pub fn execute(&self) -> Result<Report, Error> {
+        self.execute_with(FailurePolicy::Stop)
+    }
+
+    /// 失敗時の動作を指定して、計画したリネームを実行し、ジャーナルに記録します。
+    ///
+    /// 実行前に [`RenamePlan::verify`] で計画がまだ有効かを確認し、無効な場合は何も変更しません。
+    /// リネームの失敗はエラーではなく [`Status::Failed`] として結果に含めます。
+    ///
+    /// # Errors
+    ///
+    /// 計画と一致しなくなった操作がある場合、またはジャーナルの書き込みに失敗した場合
+    pub fn execute_with(&self, on_failure: FailurePolicy) -> Result<Report, Error> {
+        self.verify()?;
+        let mut run = JournalRun::new(&self.pattern, &self.separator, &self.root);
+        let mut report = Report::default();
+        let mut result = Ok(());
+        for op in &self.operations {
+            let status = match op.action {
+                Action::Skip(reason) => {
+                    info!("skipped {} ({})", escape_path(&op.src), reason);
+                    Status::Skipped(reason)
+                }
+                // 順序の誤った計画ファイルでも、上書きしない操作で既存のファイルを失わない
+                Action::Rename if fs::symlink_metadata(self.root.join(&op.dest)).is_ok() => {
+                    Status::Failed(io::Error::new(
+                        io::ErrorKind::AlreadyExists,
+                        "destination already exists",
+                    ))
+                }
+                Action::Rename | Action::Overwrite => {
+                    match fs::rename(self.root.join(&op.src), self.root.join(&op.dest)) {
+                        Ok(()) => {
+                            info!(
+                                "renamed {} -> {}",
+                                escape_path(&op.src),
+                                escape_path(&op.dest)
+                            );
+                            result = run.record(&self.root, &op.src, &op.dest);
+                            Status::Renamed
+                        }
+                        Err(err) => Status::Failed(err),
+                    }
+                }
+            };
+            let stop =
+                matches!(status, Status::Failed(_)) && on_failure != FailurePolicy::KeepGoing;
+            report.push(op.clone(), status);
+            if stop || result.is_err() {
+                break;
+            }
+        }
+
+        if on_failure == FailurePolicy::Rollback && (report.summary.failed > 0 || result.is_err()) {
+            self.rollback(&mut report, &mut run);
+        }
+
+        // 途中で失敗した場合もリネーム済みの分は記録しておく
+        if !run.entries.is_empty() {
+            journal::append(&self.root, &run)?;
+            debug!("recorded {} renames in the journal", run.entries.len());
+        }
+        result?;
+        Ok(report)
+    }
+
+    /// 完了したリネームを逆順に元に戻します。
+    ///
+    /// 元に戻したリネームはジャーナルの記録からも除き、戻せなかったものは記録に残します。
+    fn rollback(&self, report: &mut Report, run: &mut JournalRun) {
+        for (op, status) in report.results.iter().rev() {
+            if !matches!(status, Status::Renamed) {
+                continue;
+            }
+            let result = fs::rename(self.root.join(&op.dest), self.root.join(&op.src));
+            match &result {
+                Ok(()) => {
+                    info!(
+                        "rolled back {} -> {}",
+                        escape_path(&op.dest),
+                        escape_path(&op.src)
+                    );
+                    run.entries.retain(|entry| entry.new != op.dest);
+                    report.summary.renamed -= 1;
+                    report.summary.rolled_back += 1;
+                }
+                Err(err) => warn!(
+                    "failed to roll back {} -> {}: {}",
+                    escape_path(&op.dest),
+                    escape_path(&op.src),
+                    err
+                ),
+            }
+            report.rollback.push((op.clone(), result));
+        }
+    }
+}
+
+/// ディレクトリ名を正規化してパターンと照合します。
+///
+/// UTF-8 でないディレクトリ名で、一致した部分に置換文字が含まれる場合は
+/// ファイル名に書き込まないよう一致しなかったものとみなします。
+fn capture_dirname(re: &Regex, dirname: &OsStr, options: &RenameOptions) -> DirMatch {
+    let captured = DirMatch::capture(re, &options.normalize.apply(&dirname.to_string_lossy()));
+    let lossy = dirname.to_str().is_none()
+        && captured
+            .groups
+            .iter()
+            .any(|group| group.contains(char::REPLACEMENT_CHARACTER));
+    if lossy {
+        debug!(
+            "{} is not valid UTF-8 where the pattern matched",
+            escape_path(Path::new(dirname))
+        );
+        return DirMatch::default();
+    }
+    captured
+}
+
+/// 対象パスのプレフィックスを決めます。
+///
+/// プレフィックスが指定されていればそれを使い、パターンが対象パスのディレクトリ名に
+/// 一致しなければ `options.no_match` に従います。
+///
+/// # Arguments
+///
+/// * `path` - 対象パス
+/// * `captured` - ディレクトリ名から取得したプレフィックス
+/// * `options` - リネームオプション
+///
+/// # Errors
+///
+/// 一致しない場合の動作が拒否で、プレフィックスの取得元が対象パスの場合、
+/// またはディレクトリ名全体を使う場合にディレクトリ名が UTF-8 でない場合
+fn top_prefix(path: &Path, captured: String, options: &RenameOptions) -> Result<String, Error> {
+    if let Some(prefix) = &options.prefix {
+        return Ok(prefix.clone());
+    }
+    if !captured.is_empty() {
+        return Ok(captured);
+    }
+    let prefix = match &options.no_match {
+        NoMatchPolicy::Prefix(prefix) => prefix.clone(),
+        NoMatchPolicy::Dirname => match path.file_name().map(|name| name.to_str()) {
+            Some(Some(name)) if !name.is_empty() => options.normalize.apply(name).into_owned(),
+            _ => {
+                // 置換文字をファイル名に書き込まない
+                warn!(
+                    "{} has no directory name that is valid UTF-8, use --prefix",
+                    escape_path(path)
+                );
+                return Err(Error::NoMatch(path.to_path_buf()));
+            }
+        },
+        // 最も近い親ディレクトリから取得する場合は、一致したサブディレクトリだけをリネーム
+        NoMatchPolicy::Refuse if options.prefix_from == PrefixFrom::Nearest => {
+            return Ok(String::new())
+        }
+        NoMatchPolicy::Refuse => return Err(Error::NoMatch(path.to_path_buf())),
+    };
+    info!(
+        "pattern did not match {}, using prefix {:?}",
+        escape_path(path),
+        prefix
+    );
+    Ok(prefix)
+}
+
+/// [`Resolver`] での操作の処理状況
+#[derive(Clone, Copy, PartialEq)]
+enum Visit {
+    Todo,
+    Visiting,
+    Done,
+}
+
+/// 計画した操作のリネーム先の衝突を解決し、実行順に並べます。
+///
+/// 同じ計画で別の名前にリネームされるファイルは、先にリネームすれば空くため衝突とみなさず、
+/// 空けるリネームを先に実行するよう並べ替えます。互いのリネーム先が入れ替わる場合は衝突とみなします。
+struct Resolver<'a> {
+    /// 対象パス
+    root: &'a Path,
+    /// リネーム先が衝突した場合の動作
+    on_conflict: ConflictPolicy,
+    /// リネーム先を決めた操作（衝突は未解決）
+    planned: Vec<Operation>,
+    /// リネームする操作のリネーム元から計画内の位置への対応
+    sources: HashMap<PathBuf, usize>,
+    /// 各操作の処理状況
+    visits: Vec<Visit>,
+    /// リネーム先として使う名前
+    claimed: HashSet<PathBuf>,
+    /// 先にリネームして空く名前
+    vacated: HashSet<PathBuf>,
+    /// 実行順の操作
+    operations: Vec<Operation>,
+    /// 衝突時の動作が中止で衝突した操作
+    conflicts: Vec<Operation>,
+}
+
+impl<'a> Resolver<'a> {
+    fn new(root: &'a Path, on_conflict: ConflictPolicy, planned: Vec<Operation>) -> Self {
+        let sources = planned
+            .iter()
+            .enumerate()
+            .filter(|(_, op)| op.action == Action::Rename)
+            .map(|(i, op)| (op.src.clone(), i))
+            .collect();
+        Resolver {
+            root,
+            on_conflict,
+            visits: vec![Visit::Todo; planned.len()],
+            planned,
+            sources,
+            claimed: HashSet::new(),
+            vacated: HashSet::new(),
+            operations: Vec::new(),
+            conflicts: Vec::new(),
+        }
+    }
+
+    /// すべての操作の衝突を解決します。
+    ///
+    /// # Returns
+    ///
+    /// 実行順の操作と、衝突時の動作が中止で衝突した操作
+    fn run(mut self) -> (Vec<Operation>, Vec<Operation>) {
+        for i in 0..self.planned.len() {
+            self.resolve(i);
+        }
+        (self.operations, self.conflicts)
+    }
+
+    /// 計画内の位置 `i` の操作を解決します。
+    fn resolve(&mut self, i: usize) {
+        if self.visits[i] != Visit::Todo {
+            return;
+        }
+        self.visits[i] = Visit::Visiting;
+        let op = self.planned[i].clone();
+        if op.action == Action::Rename {
+            // リネーム先を空ける操作までを先に解決する（間の操作も順に解決し、
+            // 再帰モードでディレクトリを中身より先にリネームしない）
+            if let Some(&j) = self.sources.get(&op.dest) {
+                for k in i + 1..=j {
+                    self.resolve(k);
+                }
+            }
+            self.decide(op);
+        } else {
+            self.operations.push(op);
+        }
+        self.visits[i] = Visit::Done;
+    }
+
+    /// リネーム先が使われているかを判定します。
+    fn occupied(&self, dest: &Path) -> bool {
+        self.claimed.contains(dest)
+            || (!self.vacated.contains(dest) && fs::symlink_metadata(self.root.join(dest)).is_ok())
+    }
+
+    /// 衝突時の動作に従ってリネーム操作を決めます。
+    fn decide(&mut self, op: Operation) {
+        let Operation {
+            src,
+            mut dest,
+            mut action,
+        } = op;
+        if self.occupied(&dest) {
+            match self.on_conflict {
+                ConflictPolicy::Abort => {
+                    self.conflicts.push(Operation { src, dest, action });
+                    return;
+                }
+                ConflictPolicy::Skip => action = Action::Skip(SkipReason::Conflict),
+                ConflictPolicy::Counter => {
+                    let base = dest.clone();
+                    let mut counter = 1;
+                    while self.occupied(&dest) {
+                        dest = with_counter(&base, counter);
+                        counter += 1;
+                    }
+                }
+                // 計画内のリネーム先同士の衝突は上書きするとファイルが失われる
+                ConflictPolicy::Overwrite if self.claimed.contains(&dest) => {
+                    self.conflicts.push(Operation { src, dest, action });
+                    return;
+                }
+                ConflictPolicy::Overwrite => action = Action::Overwrite,
+            }
+        }
+        if !matches!(action, Action::Skip(_)) {
+            self.claimed.insert(dest.clone());
+            self.vacated.insert(src.clone());
+        }
+        self.operations.push(Operation { src, dest, action });
+    }
+}
+
+/// リネーム対象のエントリを収集します。
+///
+/// 再帰モードではディレクトリの中へ降りていき、ディレクトリ自体は種類に `dir` が
+/// 指定された場合のみ中身の後にリネームします。隠しファイルは指定がない限り除外し、
+/// 隠しディレクトリの中へも降りません。
+///
+/// # Arguments
+///
+/// * `root` - 対象パス
+/// * `rel` - 対象パスから現在のディレクトリへの相対パス
+/// * `depth` - 現在のディレクトリの深さ（対象パスは 0）
+/// * `dir_match` - 現在のディレクトリのエントリに使うマッチ結果
+/// * `options` - リネームオプション
+/// * `re` - 最も近い親ディレクトリからプレフィックスを取得する場合の正規表現
+/// * `targets` - 収集したエントリ（相対パスとマッチ結果）の格納先
+///
+/// # Errors
+///
+/// ディレクトリの読み込みに失敗した場合
+fn collect_entries(
+    root: &Path,
+    rel: &Path,
+    depth: usize,
+    dir_match: &DirMatch,
+    options: &RenameOptions,
+    re: Option<&Regex>,
+    targets: &mut Vec<(PathBuf, DirMatch)>,
+) -> io::Result<()> {
+    let mut entries = fs::read_dir(root.join(rel))?.collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        let filename = entry.file_name();
+        // 以前にサブディレクトリを対象に実行した分のジャーナルも取り消せるよう残す
+        if filename == JOURNAL_FILE_NAME {
+            continue;
+        }
+        let listed = options
+            .names
+            .as_ref()
+            .filter(|_| depth == 0)
+            .map(|names| names.contains(&filename));
+        if listed == Some(false) {
+            continue;
+        }
+        if listed.is_none()
+            && !options.include_hidden
+            && filename.as_encoded_bytes().starts_with(b".")
+        {
+            continue;
+        }
+        let entry_rel = rel.join(&filename);
+        let entry_type = EntryType::of(&entry.file_type()?);
+
+        if options.recursive
+            && entry_type == EntryType::Dir
+            && options.max_depth.is_none_or(|max| depth + 1 < max)
+        {
+            // 一致しないディレクトリと、一致した部分が UTF-8 でないディレクトリでは
+            // 親のプレフィックスを引き継ぐ
+            let sub_match = re
+                .map(|re| capture_dirname(re, &filename, options))
+                .filter(|m| !m.prefix.is_empty())
+                .unwrap_or_else(|| dir_match.clone());
+            trace!(
+                "descending into {} with prefix {:?}",
+                entry_rel.display(),
+                sub_match.prefix
+            );
+            collect_entries(
+                root,
+                &entry_rel,
+                depth + 1,
+                &sub_match,
+                options,
+                re,
+                targets,
+            )?;
+        }
+
+        let selected = if options.types.is_empty() {
+            !(options.recursive && entry_type == EntryType::Dir)
+        } else {
+            options.types.contains(&entry_type)
+        };
+        if selected && options.matches_name(&filename.to_string_lossy()) {
+            targets.push((entry_rel, dir_match.clone()));
+        } else {
+            trace!("not selected: {}", entry_rel.display());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::journal;
+    use crate::template::Template;
+    use crate::{is_mixed, Normalization};
+    use std::ffi::OsString;
+
+    fn options(prefix: &str) -> RenameOptions {
+        RenameOptions {
+            pattern: r"\d+".to_string(),
+            prefix: Some(prefix.to_string()),
+            ..Default::default()
+        }
+    }
+
+    fn rename_files(path: &Path, options: &RenameOptions) -> Result<Summary, Error> {
+        Ok(RenamePlan::new(path, options)?.execute()?.summary)
+    }
+
+    #[test]
+    fn test_rename_and_undo() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("test.txt"), "test").unwrap();
+
+        rename_files(dir.path(), &options("20241231")).unwrap();
+        assert!(dir.path().join("20241231_test.txt").exists());
+        assert!(dir.path().join(JOURNAL_FILE_NAME).exists());
+
+        let report = journal::undo(dir.path(), false).unwrap();
+        assert_eq!((report.reverted(), report.refused()), (1, 0));
+        assert!(dir.path().join("test.txt").exists());
+        assert!(!dir.path().join(JOURNAL_FILE_NAME).exists());
+    }
+
+    #[test]
+    fn test_rename_skips_prefixed() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("test.txt"), "test").unwrap();
+        fs::write(dir.path().join("20241231_done.txt"), "done").unwrap();
+
+        let summary = rename_files(dir.path(), &options("20241231")).unwrap();
+        assert_eq!(
+            summary,
+            Summary {
+                renamed: 1,
+                skipped: 1,
+                ..Default::default()
+            }
+        );
+        assert!(dir.path().join("20241231_test.txt").exists());
+        assert!(dir.path().join("20241231_done.txt").exists());
+
+        // 2回目の実行では何も変更しない
+        let summary = rename_files(dir.path(), &options("20241231")).unwrap();
+        assert_eq!(
+            summary,
+            Summary {
+                renamed: 0,
+                skipped: 2,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_rename_prefix_from_dirname() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("20241231_sample");
+        fs::create_dir(&target).unwrap();
+        fs::write(target.join("test.txt"), "test").unwrap();
+
+        let options = RenameOptions {
+            prefix: None,
+            ..options("")
+        };
+        let plan = RenamePlan::new(&target, &options).unwrap();
+        assert_eq!(plan.root(), target);
+        assert_eq!(
+            plan.operations(),
+            [Operation {
+                src: PathBuf::from("test.txt"),
+                dest: PathBuf::from("20241231_test.txt"),
+                action: Action::Rename,
+            }]
+        );
+        assert_eq!(plan.summary().renamed, 1);
+        // 計画しただけではリネームしない
+        assert!(target.join("test.txt").exists());
+    }
+
+    #[test]
+    fn test_rename_skips_unmatched_dirname() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("sample");
+        let dated = target.join("20250101_extra");
+        fs::create_dir_all(&dated).unwrap();
+        fs::write(target.join("a.txt"), "a").unwrap();
+        fs::write(dated.join("b.txt"), "b").unwrap();
+
+        let options = RenameOptions {
+            prefix: None,
+            recursive: true,
+            prefix_from: PrefixFrom::Nearest,
+            ..options("")
+        };
+        let plan = RenamePlan::new(&target, &options).unwrap();
+        assert_eq!(
+            plan.operations(),
+            [
+                Operation {
+                    src: PathBuf::from("20250101_extra/b.txt"),
+                    dest: PathBuf::from("20250101_extra/20250101_b.txt"),
+                    action: Action::Rename,
+                },
+                Operation {
+                    src: PathBuf::from("a.txt"),
+                    dest: PathBuf::from("a.txt"),
+                    action: Action::Skip(SkipReason::NoMatch),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_rename_recursive() {
+        let dir = tempfile::tempdir().unwrap();
+        let raw = dir.path().join("raw");
+        let nested = dir.path().join("20250101_extra").join("deep");
+        fs::create_dir_all(&raw).unwrap();
+        fs::create_dir_all(&nested).unwrap();
+        fs::write(raw.join("a.cr2"), "a").unwrap();
+        fs::write(nested.join("b.jpg"), "b").unwrap();
+        fs::write(raw.join(JOURNAL_FILE_NAME), "").unwrap();
+
+        let options = RenameOptions {
+            recursive: true,
+            prefix_from: PrefixFrom::Nearest,
+            include_hidden: true,
+            ..options("20241231")
+        };
+        let summary = rename_files(dir.path(), &options).unwrap();
+        assert_eq!(summary.renamed, 2);
+        assert!(raw.join(JOURNAL_FILE_NAME).exists());
+        assert!(raw.join("20241231_a.cr2").exists());
+        assert!(nested.join("20250101_b.jpg").exists());
+
+        journal::undo(dir.path(), false).unwrap();
+        assert!(raw.join("a.cr2").exists());
+        assert!(nested.join("b.jpg").exists());
+    }
+
+    #[test]
+    fn test_rename_recursive_max_depth() {
+        let dir = tempfile::tempdir().unwrap();
+        let sub = dir.path().join("sub");
+        fs::create_dir(&sub).unwrap();
+        fs::write(dir.path().join("a.txt"), "a").unwrap();
+        fs::write(sub.join("b.txt"), "b").unwrap();
+
+        let options = RenameOptions {
+            recursive: true,
+            max_depth: Some(1),
+            ..options("20241231")
+        };
+        let summary = rename_files(dir.path(), &options).unwrap();
+        assert_eq!(summary.renamed, 1);
+        assert!(dir.path().join("20241231_a.txt").exists());
+        assert!(sub.join("b.txt").exists());
+    }
+
+    #[test]
+    fn test_rename_separator() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a.txt"), "a").unwrap();
+        fs::write(dir.path().join("20241231b.txt"), "b").unwrap();
+
+        let options = RenameOptions {
+            separator: "".to_string(),
+            ..options("20241231")
+        };
+        let summary = rename_files(dir.path(), &options).unwrap();
+        assert_eq!(
+            summary,
+            Summary {
+                renamed: 1,
+                skipped: 1,
+                ..Default::default()
+            }
+        );
+        assert!(dir.path().join("20241231a.txt").exists());
+
+        let runs = journal::load(dir.path()).unwrap();
+        assert_eq!(runs[0].separator, "");
+    }
+
+    #[test]
+    fn test_rename_template() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("20241231_shoot");
+        fs::create_dir(&target).unwrap();
+        fs::write(target.join("a.jpg"), "a").unwrap();
+        fs::write(target.join("b.jpg"), "b").unwrap();
+
+        let options = RenameOptions {
+            pattern: r"(\d{4})(\d{2})(\d{2})".to_string(),
+            template: Some(Template::parse("{1}-{2}-{3}_{counter:2}_{name}{ext}").unwrap()),
+            ..options("20241231")
+        };
+        let summary = rename_files(&target, &options).unwrap();
+        assert_eq!(summary.renamed, 2);
+        assert!(target.join("2024-12-31_01_a.jpg").exists());
+        assert!(target.join("2024-12-31_02_b.jpg").exists());
+
+        let summary = rename_files(&target, &options).unwrap();
+        assert_eq!(
+            summary,
+            Summary {
+                renamed: 0,
+                skipped: 2,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_execute_parent_after_subdirectory() {
+        let dir = tempfile::tempdir().unwrap();
+        let sub = dir.path().join("sub");
+        fs::create_dir(&sub).unwrap();
+        fs::write(sub.join("a.txt"), "a").unwrap();
+
+        // サブディレクトリの中身をリネームしても、親の計画のディレクトリは変更とみなさない
+        let parent = RenamePlan::new(dir.path(), &options("P")).unwrap();
+        RenamePlan::new(&sub, &options("S"))
+            .unwrap()
+            .execute()
+            .unwrap();
+        parent.execute().unwrap();
+        assert!(dir.path().join("P_sub").join("S_a.txt").exists());
+    }
+
+    #[test]
+    fn test_rename_rejects_path_separators() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("20241231_a");
+        fs::create_dir(&target).unwrap();
+        fs::write(target.join("x.txt"), "x").unwrap();
+
+        let template = RenameOptions {
+            template: Some(Template::parse("../{name}{ext}").unwrap()),
+            ..options("20241231")
+        };
+        let separator = RenameOptions {
+            separator: "/".to_string(),
+            ..options("..")
+        };
+        for options in [template, separator] {
+            let err = RenamePlan::new(&target, &options).unwrap_err();
+            assert!(matches!(err, Error::InvalidNames(ops) if ops.len() == 1));
+        }
+        assert!(target.join("x.txt").exists());
+        assert!(!dir.path().join("x.txt").exists());
+    }
+
+    #[test]
+    fn test_rename_strip() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("20241231_a.txt"), "a").unwrap();
+        fs::write(dir.path().join("b.txt"), "b").unwrap();
+        fs::write(dir.path().join("20241231_c.txt"), "new").unwrap();
+        fs::write(dir.path().join("c.txt"), "old").unwrap();
+
+        let options = RenameOptions {
+            strip: true,
+            on_conflict: ConflictPolicy::Skip,
+            ..options("20241231")
+        };
+        let summary = rename_files(dir.path(), &options).unwrap();
+        assert_eq!(
+            summary,
+            Summary {
+                renamed: 1,
+                skipped: 3,
+                ..Default::default()
+            }
+        );
+        assert!(dir.path().join("a.txt").exists());
+        assert!(dir.path().join("20241231_c.txt").exists());
+        assert_eq!(fs::read_to_string(dir.path().join("c.txt")).unwrap(), "old");
+    }
+
+    #[test]
+    fn test_rename_strip_regex() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("20230101_a.txt"), "a").unwrap();
+        fs::write(dir.path().join("x20230101_b.txt"), "b").unwrap();
+
+        let options = RenameOptions {
+            strip: true,
+            strip_regex: Some(Regex::new(r"\d{8}").unwrap()),
+            ..options("")
+        };
+        let summary = rename_files(dir.path(), &options).unwrap();
+        assert_eq!(
+            summary,
+            Summary {
+                renamed: 1,
+                skipped: 1,
+                ..Default::default()
+            }
+        );
+        assert!(dir.path().join("a.txt").exists());
+        assert!(dir.path().join("x20230101_b.txt").exists());
+    }
+
+    #[test]
+    fn test_plan_conflict_abort() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("test.txt"), "new").unwrap();
+        fs::write(dir.path().join("other.txt"), "other").unwrap();
+        fs::write(dir.path().join("20241231_test.txt"), "old").unwrap();
+
+        // 既存のファイルはプレフィックス付きとしてスキップされ、リネーム先は空かない
+        let err = rename_files(dir.path(), &options("20241231")).unwrap_err();
+        assert!(matches!(err, Error::Conflicts(ops) if ops.len() == 1));
+        assert!(dir.path().join("other.txt").exists());
+        assert_eq!(
+            fs::read_to_string(dir.path().join("20241231_test.txt")).unwrap(),
+            "old"
+        );
+    }
+
+    #[test]
+    fn test_plan_conflict_policies() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("test.txt"), "new").unwrap();
+        fs::write(dir.path().join("20241231_test.txt"), "old").unwrap();
+        fs::write(dir.path().join("20241231_test_1.txt"), "old").unwrap();
+
+        let plan = |on_conflict| {
+            let options = RenameOptions {
+                on_conflict,
+                ..options("20241231")
+            };
+            let plan = RenamePlan::new(dir.path(), &options).unwrap();
+            plan.operations()
+                .iter()
+                .find(|op| op.src == Path::new("test.txt"))
+                .cloned()
+                .unwrap()
+        };
+
+        assert_eq!(
+            plan(ConflictPolicy::Skip).action,
+            Action::Skip(SkipReason::Conflict)
+        );
+        let op = plan(ConflictPolicy::Counter);
+        assert_eq!(op.dest, Path::new("20241231_test_2.txt"));
+        assert_eq!(op.action, Action::Rename);
+        assert_eq!(plan(ConflictPolicy::Overwrite).action, Action::Overwrite);
+
+        // リネーム先が同じ計画でリネームされるファイルの場合は、そのリネームを先に実行する
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a.txt"), "A").unwrap();
+        fs::write(dir.path().join("p_a.txt"), "OLD").unwrap();
+        let overwrite = RenameOptions {
+            on_conflict: ConflictPolicy::Overwrite,
+            reprefix: true,
+            ..options("p")
+        };
+        let plan = RenamePlan::new(dir.path(), &overwrite).unwrap();
+        let ops = plan
+            .operations()
+            .iter()
+            .map(|op| {
+                (
+                    op.src.to_str().unwrap(),
+                    op.dest.to_str().unwrap(),
+                    op.action,
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            ops,
+            [
+                ("p_a.txt", "p_p_a.txt", Action::Rename),
+                ("a.txt", "p_a.txt", Action::Rename),
+            ]
+        );
+        plan.execute().unwrap();
+        assert_eq!(
+            fs::read_to_string(dir.path().join("p_p_a.txt")).unwrap(),
+            "OLD"
+        );
+        assert_eq!(fs::read_to_string(dir.path().join("p_a.txt")).unwrap(), "A");
+
+        journal::undo(dir.path(), false).unwrap();
+        assert_eq!(fs::read_to_string(dir.path().join("a.txt")).unwrap(), "A");
+        assert_eq!(
+            fs::read_to_string(dir.path().join("p_a.txt")).unwrap(),
+            "OLD"
+        );
+    }
+
+    #[test]
+    fn test_plan_entry_filters() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("photo.jpg"), "photo").unwrap();
+        fs::write(dir.path().join(".DS_Store"), "").unwrap();
+        fs::create_dir(dir.path().join("sub")).unwrap();
+
+        let sources = |options: &RenameOptions| {
+            RenamePlan::new(dir.path(), options)
+                .unwrap()
+                .operations()
+                .iter()
+                .map(|op| op.src.clone())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            sources(&options("20241231")),
+            [Path::new("photo.jpg"), Path::new("sub")]
+        );
+        let files = RenameOptions {
+            types: vec![EntryType::File],
+            ..options("20241231")
+        };
+        assert_eq!(sources(&files), [Path::new("photo.jpg")]);
+        let hidden = RenameOptions {
+            types: vec![EntryType::File],
+            include_hidden: true,
+            ..options("20241231")
+        };
+        assert_eq!(
+            sources(&hidden),
+            [Path::new(".DS_Store"), Path::new("photo.jpg")]
+        );
+        let listed = RenameOptions {
+            names: Some([".DS_Store", "sub"].map(OsString::from).into()),
+            ..options("20241231")
+        };
+        assert_eq!(sources(&listed), [Path::new(".DS_Store"), Path::new("sub")]);
+    }
+
+    #[test]
+    fn test_rename_recursive_dirs_after_contents() {
+        let dir = tempfile::tempdir().unwrap();
+        let sub = dir.path().join("sub");
+        fs::create_dir(&sub).unwrap();
+        fs::write(sub.join("a.txt"), "a").unwrap();
+
+        let options = RenameOptions {
+            recursive: true,
+            types: vec![EntryType::File, EntryType::Dir],
+            ..options("20241231")
+        };
+        rename_files(dir.path(), &options).unwrap();
+        assert!(dir
+            .path()
+            .join("20241231_sub")
+            .join("20241231_a.txt")
+            .exists());
+    }
+
+    #[test]
+    fn test_execute_keep_going() {
+        let dir = tempfile::tempdir().unwrap();
+        // 空でないディレクトリへの上書きは失敗する
+        fs::create_dir(dir.path().join("a")).unwrap();
+        fs::create_dir(dir.path().join("20241231_a")).unwrap();
+        fs::write(dir.path().join("20241231_a").join("x.txt"), "x").unwrap();
+        fs::write(dir.path().join("b.txt"), "b").unwrap();
+
+        let options = RenameOptions {
+            on_conflict: ConflictPolicy::Overwrite,
+            ..options("20241231")
+        };
+        let plan = RenamePlan::new(dir.path(), &options).unwrap();
+        let report = plan.execute().unwrap();
+        assert_eq!(
+            report.summary,
+            Summary {
+                renamed: 0,
+                skipped: 1,
+                failed: 1,
+                ..Default::default()
+            }
+        );
+        assert!(dir.path().join("b.txt").exists());
+
+        let report = plan.execute_with(FailurePolicy::KeepGoing).unwrap();
+        assert_eq!(
+            report.summary,
+            Summary {
+                renamed: 1,
+                skipped: 1,
+                failed: 1,
+                ..Default::default()
+            }
+        );
+        assert!(dir.path().join("20241231_b.txt").exists());
+    }
+
+    #[test]
+    fn test_execute_rollback() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a.txt"), "a").unwrap();
+        // 空でないディレクトリへの上書きは失敗する
+        fs::create_dir(dir.path().join("b")).unwrap();
+        fs::create_dir(dir.path().join("20241231_b")).unwrap();
+        fs::write(dir.path().join("20241231_b").join("x.txt"), "x").unwrap();
+
+        let options = RenameOptions {
+            reprefix: true,
+            types: vec![EntryType::File, EntryType::Dir],
+            include: vec![glob::Pattern::new("[ab]*").unwrap()],
+            on_conflict: ConflictPolicy::Overwrite,
+            ..options("20241231")
+        };
+        let plan = RenamePlan::new(dir.path(), &options).unwrap();
+        let report = plan.execute_with(FailurePolicy::Rollback).unwrap();
+        assert_eq!(
+            report.summary,
+            Summary {
+                renamed: 0,
+                failed: 1,
+                rolled_back: 1,
+                ..Default::default()
+            }
+        );
+        assert!(report.rollback.iter().all(|(_, result)| result.is_ok()));
+        assert!(dir.path().join("a.txt").exists());
+        assert!(!dir.path().join(JOURNAL_FILE_NAME).exists());
+    }
+
+    #[test]
+    fn test_rename_no_match_policies() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("sample");
+        fs::create_dir(&target).unwrap();
+        fs::write(target.join("a.txt"), "a").unwrap();
+        let options = |no_match| RenameOptions {
+            pattern: r"\d{8}".to_string(),
+            no_match,
+            ..Default::default()
+        };
+        let dests = |options: &RenameOptions| {
+            let plan = RenamePlan::new(&target, options).unwrap();
+            plan.operations()
+                .iter()
+                .map(|op| op.dest.clone())
+                .collect::<Vec<_>>()
+        };
+
+        // 既定では `_a.txt` のような名前にせず、何もリネームしない
+        let err = RenamePlan::new(&target, &options(NoMatchPolicy::Refuse)).unwrap_err();
+        assert!(matches!(err, Error::NoMatch(path) if path == target));
+        assert_eq!(
+            dests(&options(NoMatchPolicy::Prefix("P".to_string()))),
+            [Path::new("P_a.txt")]
+        );
+        assert_eq!(
+            dests(&options(NoMatchPolicy::Dirname)),
+            [Path::new("sample_a.txt")]
+        );
+
+        // 最も近い親ディレクトリから取得する場合は一致したサブディレクトリだけをリネーム
+        fs::create_dir(target.join("20241231")).unwrap();
+        fs::write(target.join("20241231").join("b.txt"), "b").unwrap();
+        let nearest = RenameOptions {
+            recursive: true,
+            prefix_from: PrefixFrom::Nearest,
+            ..options(NoMatchPolicy::Refuse)
+        };
+        rename_files(&target, &nearest).unwrap();
+        assert!(target.join("a.txt").exists());
+        assert!(target.join("20241231").join("20241231_b.txt").exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_rename_non_utf8_name() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let name = std::ffi::OsStr::from_bytes(b"\x83e\x83X\x83g.txt");
+        fs::write(dir.path().join(name), "sjis").unwrap();
+
+        rename_files(dir.path(), &options("20241231")).unwrap();
+        let renamed = std::ffi::OsStr::from_bytes(b"20241231_\x83e\x83X\x83g.txt");
+        assert!(dir.path().join(renamed).exists());
+
+        journal::undo(dir.path(), false).unwrap();
+        assert!(dir.path().join(name).exists());
+
+        // UTF-8 でないディレクトリ名から置換文字をプレフィックスとして書き込まない
+        let target = dir.path().join(std::ffi::OsStr::from_bytes(b"2024\x83e"));
+        fs::create_dir(&target).unwrap();
+        fs::write(target.join("a.txt"), "a").unwrap();
+        let err = rename_files(&target, &RenameOptions::default()).unwrap_err();
+        assert!(matches!(err, Error::NoMatch(_)));
+        let dirname = RenameOptions {
+            pattern: "x".to_string(),
+            no_match: NoMatchPolicy::Dirname,
+            ..Default::default()
+        };
+        let err = rename_files(&target, &dirname).unwrap_err();
+        assert!(matches!(err, Error::NoMatch(_)));
+        assert!(target.join("a.txt").exists());
+        let digits = RenameOptions {
+            pattern: r"\d+".to_string(),
+            ..Default::default()
+        };
+        rename_files(&target, &digits).unwrap();
+        assert!(target.join("2024_a.txt").exists());
+    }
+
+    #[test]
+    fn test_rename_normalize() {
+        let dir = tempfile::tempdir().unwrap();
+        let (nfc, nfd) = ("\u{30d7}\u{30ed}", "\u{30d5}\u{309a}\u{30ed}");
+        let target = dir.path().join(format!("{}_2024", nfd));
+        fs::create_dir(&target).unwrap();
+        fs::write(target.join(format!("{}_a.txt", nfd)), "a").unwrap();
+        fs::write(target.join(format!("{}.txt", nfd)), "b").unwrap();
+
+        let options = RenameOptions {
+            pattern: nfc.to_string(),
+            prefix: None,
+            normalize: Normalization::Nfc,
+            normalize_names: true,
+            ..Default::default()
+        };
+        let plan = RenamePlan::new(&target, &options).unwrap();
+        assert_eq!(
+            plan.operations()
+                .iter()
+                .map(|op| (op.dest.to_str().unwrap(), op.action))
+                .collect::<Vec<_>>(),
+            [
+                (format!("{}_{}.txt", nfc, nfc).as_str(), Action::Rename),
+                (
+                    format!("{}_{}_a.txt", nfc, nfc).as_str(),
+                    Action::Skip(SkipReason::AlreadyPrefixed)
+                ),
+            ]
+        );
+
+        // 元の名前のまま付ける場合は、プレフィックスも元の名前と同じ NFD にする
+        let plan = RenamePlan::new(
+            &target,
+            &RenameOptions {
+                normalize_names: false,
+                ..options
+            },
+        )
+        .unwrap();
+        let dest = plan.operations()[0].dest.to_str().unwrap();
+        assert_eq!(dest, format!("{}_{}.txt", nfd, nfd));
+        assert!(!is_mixed(dest));
+    }
+
+    #[test]
+    fn test_undo_refuses_changed_file() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("test.txt"), "test").unwrap();
+
+        rename_files(dir.path(), &options("20241231")).unwrap();
+        fs::write(dir.path().join("20241231_test.txt"), "changed").unwrap();
+
+        let report = journal::undo(dir.path(), false).unwrap();
+        assert_eq!((report.reverted(), report.refused()), (0, 1));
+        assert!(dir.path().join("20241231_test.txt").exists());
+        assert_eq!(journal::load(dir.path()).unwrap().len(), 1);
+    }
+}
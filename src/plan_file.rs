@@ -0,0 +1,336 @@
+//! リネーム計画のファイルへの書き出しと読み込み
+//!
+//! 計画ファイルにはリネーム元のサイズと更新日時も記録し、適用時に計画作成後の変更を検出します。
+
+use crate::os_name::escape_path;
+use crate::{Action, Error, Operation, PlanFormat, RenamePlan, SkipReason};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Component, Path, PathBuf};
+
+/// JSON 形式の計画ファイル
+#[derive(Serialize, Deserialize)]
+struct PlanFile {
+    /// 対象パス（絶対パス）
+    #[serde(with = "crate::os_name")]
+    root: PathBuf,
+    /// 使用した正規表現パターン
+    pattern: String,
+    /// 使用した区切り文字
+    separator: String,
+    /// 実行順のリネーム操作
+    operations: Vec<PlanRecord>,
+}
+
+/// 計画ファイルの1操作分の記録
+#[derive(Serialize, Deserialize)]
+struct PlanRecord {
+    /// 操作の種類（rename, overwrite, skip）
+    action: String,
+    /// スキップする理由
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    reason: Option<String>,
+    /// リネーム元（対象パスからの相対パス）
+    #[serde(with = "crate::os_name")]
+    src: PathBuf,
+    /// リネーム先（対象パスからの相対パス）
+    #[serde(with = "crate::os_name")]
+    dest: PathBuf,
+    /// 計画作成時のリネーム元のファイルサイズ
+    size: u64,
+    /// 計画作成時のリネーム元の更新日時（UNIX 時間、ナノ秒）
+    modified: u64,
+}
+
+/// TSV 形式の見出し行
+const TSV_HEADER: &str = "action\treason\tsrc\tdest\tsize\tmodified";
+
+impl PlanRecord {
+    /// 操作から記録を作成します。
+    fn new(op: &Operation, (size, modified): (u64, u64)) -> Self {
+        let reason = match op.action {
+            Action::Skip(reason) => Some(reason.id().to_string()),
+            Action::Rename | Action::Overwrite => None,
+        };
+        PlanRecord {
+            action: op.action.id().to_string(),
+            reason,
+            src: op.src.clone(),
+            dest: op.dest.clone(),
+            size,
+            modified,
+        }
+    }
+
+    /// 記録を操作に変換します。
+    ///
+    /// # Errors
+    ///
+    /// 操作の種類またはスキップする理由が不正な場合、
+    /// またはリネーム元とリネーム先が対象パス内の同じディレクトリを指していない場合
+    fn into_operation(self) -> io::Result<(Operation, (u64, u64))> {
+        // 絶対パスや `..` を含むパスで対象パスの外のファイルをリネームしない
+        for path in [&self.src, &self.dest] {
+            let relative = path.file_name().is_some()
+                && path.components().all(|c| matches!(c, Component::Normal(_)));
+            if !relative {
+                return Err(invalid(format!(
+                    "{} is not a path inside the planned directory",
+                    escape_path(path)
+                )));
+            }
+        }
+        if self.src.parent() != self.dest.parent() {
+            return Err(invalid(format!(
+                "{} and {} are not in the same directory",
+                escape_path(&self.src),
+                escape_path(&self.dest)
+            )));
+        }
+        let action = match (self.action.as_str(), self.reason.as_deref()) {
+            ("rename", _) => Action::Rename,
+            ("overwrite", _) => Action::Overwrite,
+            ("skip", Some("already-prefixed")) => Action::Skip(SkipReason::AlreadyPrefixed),
+            ("skip", Some("not-prefixed")) => Action::Skip(SkipReason::NotPrefixed),
+            ("skip", Some("conflict")) => Action::Skip(SkipReason::Conflict),
+            ("skip", Some("no-match")) => Action::Skip(SkipReason::NoMatch),
+            ("skip", Some("undecodable")) => Action::Skip(SkipReason::Undecodable),
+            (action, reason) => {
+                return Err(invalid(format!(
+                    "unknown action '{}' (reason '{}')",
+                    action,
+                    reason.unwrap_or_default()
+                )));
+            }
+        };
+        let op = Operation {
+            src: self.src,
+            dest: self.dest,
+            action,
+        };
+        Ok((op, (self.size, self.modified)))
+    }
+}
+
+/// 計画ファイルの内容が不正であることを表すエラーを作成します。
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// TSV の1フィールドとして書けるかを確認します。
+fn tsv_field(value: &str) -> io::Result<&str> {
+    if value.contains(['\t', '\n', '\r']) {
+        return Err(invalid(format!("{:?} cannot be written as TSV", value)));
+    }
+    Ok(value)
+}
+
+/// パスを TSV の1フィールドとして書けるかを確認します。UTF-8 でない名前は JSON でのみ書けます。
+fn tsv_path(path: &Path) -> io::Result<&str> {
+    let value = path.to_str().ok_or_else(|| {
+        invalid(format!(
+            "{} is not valid UTF-8 and cannot be written as TSV, use JSON",
+            escape_path(path)
+        ))
+    })?;
+    tsv_field(value)
+}
+
+impl RenamePlan {
+    /// 計画をファイルに書き出します。
+    ///
+    /// 対象パスは別の作業ディレクトリからも適用できるよう絶対パスで記録します。
+    ///
+    /// # Arguments
+    ///
+    /// * `writer` - 書き出し先
+    /// * `format` - 計画ファイルの形式
+    ///
+    /// # Errors
+    ///
+    /// 書き込みに失敗した場合、または TSV で表せない名前がある場合
+    pub fn write_to(&self, mut writer: impl Write, format: PlanFormat) -> io::Result<()> {
+        let root = fs::canonicalize(self.root()).unwrap_or_else(|_| self.root().to_path_buf());
+        let file = PlanFile {
+            root,
+            pattern: self.pattern().to_string(),
+            separator: self.separator().to_string(),
+            operations: self
+                .fingerprinted()
+                .map(|(op, fp)| PlanRecord::new(op, fp))
+                .collect(),
+        };
+        match format {
+            PlanFormat::Json => {
+                serde_json::to_writer_pretty(&mut writer, &file)?;
+                writeln!(writer)?;
+            }
+            PlanFormat::Tsv => {
+                writeln!(writer, "# root\t{}", tsv_path(&file.root)?)?;
+                writeln!(writer, "# pattern\t{}", tsv_field(&file.pattern)?)?;
+                writeln!(writer, "# separator\t{}", tsv_field(&file.separator)?)?;
+                writeln!(writer, "{}", TSV_HEADER)?;
+                for record in &file.operations {
+                    writeln!(
+                        writer,
+                        "{}\t{}\t{}\t{}\t{}\t{}",
+                        record.action,
+                        record.reason.as_deref().unwrap_or_default(),
+                        tsv_path(&record.src)?,
+                        tsv_path(&record.dest)?,
+                        record.size,
+                        record.modified
+                    )?;
+                }
+            }
+        }
+        writer.flush()
+    }
+
+    /// 計画ファイルを読み込みます。形式は内容から判定します。
+    ///
+    /// # Errors
+    ///
+    /// 読み込みに失敗した場合、または計画ファイルの内容が不正な場合
+    pub fn read_from(mut reader: impl Read) -> Result<Self, Error> {
+        let mut text = String::new();
+        reader.read_to_string(&mut text)?;
+        let file = if text.trim_start().starts_with('{') {
+            serde_json::from_str(&text).map_err(io::Error::from)?
+        } else {
+            parse_tsv(&text)?
+        };
+        let operations = file
+            .operations
+            .into_iter()
+            .map(PlanRecord::into_operation)
+            .collect::<io::Result<_>>()?;
+        Ok(RenamePlan::from_parts(
+            file.root,
+            file.pattern,
+            file.separator,
+            operations,
+        ))
+    }
+}
+
+/// TSV 形式の計画ファイルを解析します。
+///
+/// # Errors
+///
+/// 対象パスがない、または行の形式が不正な場合
+fn parse_tsv(text: &str) -> io::Result<PlanFile> {
+    let mut root = None;
+    let mut pattern = String::new();
+    let mut separator = "_".to_string();
+    let mut operations = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        if line.is_empty() || line == TSV_HEADER {
+            continue;
+        }
+        if let Some(meta) = line.strip_prefix("# ") {
+            match meta.split_once('\t') {
+                Some(("root", value)) => root = Some(PathBuf::from(value)),
+                Some(("pattern", value)) => pattern = value.to_string(),
+                Some(("separator", value)) => separator = value.to_string(),
+                _ => {}
+            }
+            continue;
+        }
+        let fields = line.split('\t').collect::<Vec<_>>();
+        let [action, reason, src, dest, size, modified] = fields[..] else {
+            return Err(invalid(format!("line {}: expected 6 fields", index + 1)));
+        };
+        let number = |value: &str| {
+            value
+                .parse()
+                .map_err(|_| invalid(format!("line {}: invalid number '{}'", index + 1, value)))
+        };
+        operations.push(PlanRecord {
+            action: action.to_string(),
+            reason: (!reason.is_empty()).then(|| reason.to_string()),
+            src: PathBuf::from(src),
+            dest: PathBuf::from(dest),
+            size: number(size)?,
+            modified: number(modified)?,
+        });
+    }
+    Ok(PlanFile {
+        root: root.ok_or_else(|| invalid("missing '# root' line".to_string()))?,
+        pattern,
+        separator,
+        operations,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::RenameOptions;
+
+    #[test]
+    fn test_plan_file_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a.txt"), "a").unwrap();
+        fs::write(dir.path().join("20241231_b.txt"), "b").unwrap();
+        let options = RenameOptions {
+            prefix: Some("20241231".to_string()),
+            ..Default::default()
+        };
+        let plan = RenamePlan::new(dir.path(), &options).unwrap();
+
+        for format in [PlanFormat::Json, PlanFormat::Tsv] {
+            let mut buf = Vec::new();
+            plan.write_to(&mut buf, format).unwrap();
+            let loaded = RenamePlan::read_from(buf.as_slice()).unwrap();
+            assert_eq!(loaded.root(), fs::canonicalize(dir.path()).unwrap());
+            assert_eq!(loaded.operations(), plan.operations());
+            assert_eq!(loaded.separator(), "_");
+        }
+    }
+
+    #[test]
+    fn test_read_rejects_paths_outside_root() {
+        let plan = |src: &str, dest: &str| {
+            format!(
+                r#"{{"root":"/tmp","pattern":"","separator":"_","operations":[{{"action":"rename","src":{:?},"dest":{:?},"size":0,"modified":0}}]}}"#,
+                src, dest
+            )
+        };
+        assert!(RenamePlan::read_from(plan("sub/a.txt", "sub/P_a.txt").as_bytes()).is_ok());
+        for (src, dest) in [
+            ("a.txt", "../a.txt"),
+            ("a.txt", "/etc/a.txt"),
+            ("/etc/passwd", "a.txt"),
+            ("./a.txt", "P_a.txt"),
+            ("a.txt", "sub/P_a.txt"),
+            ("", "a.txt"),
+        ] {
+            let err = RenamePlan::read_from(plan(src, dest).as_bytes()).unwrap_err();
+            assert!(matches!(err, Error::Io(err) if err.kind() == io::ErrorKind::InvalidData));
+        }
+    }
+
+    #[test]
+    fn test_apply_refuses_changed_source() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a.txt"), "a").unwrap();
+        fs::write(dir.path().join("b.txt"), "b").unwrap();
+        let options = RenameOptions {
+            prefix: Some("20241231".to_string()),
+            ..Default::default()
+        };
+        let mut buf = Vec::new();
+        RenamePlan::new(dir.path(), &options)
+            .unwrap()
+            .write_to(&mut buf, PlanFormat::Json)
+            .unwrap();
+
+        fs::write(dir.path().join("b.txt"), "changed").unwrap();
+        let plan = RenamePlan::read_from(buf.as_slice()).unwrap();
+        let err = plan.execute().unwrap_err();
+        assert!(matches!(err, Error::Stale(ops) if ops[0].src == Path::new("b.txt")));
+        assert!(dir.path().join("a.txt").exists());
+    }
+}
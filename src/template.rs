@@ -0,0 +1,266 @@
+//! リネーム後の名前のテンプレートとディレクトリ名のマッチ結果
+
+use regex::{bytes, Regex};
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+
+/// ディレクトリ名の正規表現マッチ結果
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DirMatch {
+    /// プレフィックス（通常はマッチ全体）
+    pub prefix: String,
+    /// 番号付きキャプチャグループ（0 はマッチ全体、一致しなかったグループは空文字列）
+    pub groups: Vec<String>,
+    /// 名前付きキャプチャグループ
+    pub named: HashMap<String, String>,
+}
+
+impl DirMatch {
+    /// 正規表現でディレクトリ名を検索し、キャプチャグループを取り出します。
+    ///
+    /// 一致しない場合はプレフィックスが空のマッチ結果を返します。
+    pub fn capture(re: &Regex, dirname: &str) -> Self {
+        let Some(caps) = re.captures(dirname) else {
+            return DirMatch::default();
+        };
+        let text = |m: Option<regex::Match>| m.map_or_else(String::new, |m| m.as_str().into());
+        let named = re
+            .capture_names()
+            .flatten()
+            .map(|name| (name.to_string(), text(caps.name(name))))
+            .collect();
+        DirMatch {
+            prefix: text(caps.get(0)),
+            groups: caps.iter().map(text).collect(),
+            named,
+        }
+    }
+}
+
+/// テンプレートの構成要素
+#[derive(Clone, Debug, PartialEq)]
+enum Segment {
+    /// そのまま出力する文字列
+    Literal(String),
+    /// `{0}`, `{1}`, ... 番号付きキャプチャグループ
+    Group(usize),
+    /// `{year}` などの名前付きキャプチャグループ
+    Named(String),
+    /// `{prefix}` プレフィックス
+    Prefix,
+    /// `{name}` 拡張子を除いた元の名前
+    Name,
+    /// `{ext}` ドットを含む元の拡張子
+    Ext,
+    /// `{counter}`, `{counter:3}` 連番（桁数指定でゼロ埋め）
+    Counter(usize),
+}
+
+/// リネーム後の名前のテンプレート
+///
+/// `{1}-{2}-{3}_{name}{ext}` のように、ディレクトリ名のキャプチャグループと
+/// 元の名前のプレースホルダを組み合わせて指定します。`{{` と `}}` は波括弧そのものを表します。
+#[derive(Clone, Debug, PartialEq)]
+pub struct Template {
+    segments: Vec<Segment>,
+}
+
+impl Template {
+    /// テンプレート文字列を解析します。
+    ///
+    /// # Errors
+    ///
+    /// 波括弧が閉じていない、またはプレースホルダが空や不正な場合
+    pub fn parse(template: &str) -> Result<Self, String> {
+        let mut segments = Vec::new();
+        let mut literal = String::new();
+        let mut chars = template.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '{' => {
+                    let mut key = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => key.push(c),
+                            None => return Err(format!("unclosed placeholder '{{{}'", key)),
+                        }
+                    }
+                    if !literal.is_empty() {
+                        segments.push(Segment::Literal(std::mem::take(&mut literal)));
+                    }
+                    segments.push(Self::parse_placeholder(&key)?);
+                }
+                '}' => return Err("unmatched '}' (use '}}' for a literal brace)".to_string()),
+                c => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            segments.push(Segment::Literal(literal));
+        }
+        Ok(Template { segments })
+    }
+
+    /// プレースホルダ1つを解析します。
+    fn parse_placeholder(key: &str) -> Result<Segment, String> {
+        if let Ok(index) = key.parse() {
+            return Ok(Segment::Group(index));
+        }
+        match key.split_once(':') {
+            Some(("counter", width)) => width
+                .parse()
+                .map(Segment::Counter)
+                .map_err(|_| format!("invalid counter width '{}'", width)),
+            Some(_) => Err(format!("invalid placeholder '{{{}}}'", key)),
+            None => match key {
+                "" => Err("empty placeholder '{}'".to_string()),
+                "prefix" => Ok(Segment::Prefix),
+                "name" => Ok(Segment::Name),
+                "ext" => Ok(Segment::Ext),
+                "counter" => Ok(Segment::Counter(0)),
+                name => Ok(Segment::Named(name.to_string())),
+            },
+        }
+    }
+
+    /// テンプレートが参照するキャプチャグループが正規表現に存在するかを検証します。
+    ///
+    /// # Errors
+    ///
+    /// 存在しない番号または名前のグループを参照している場合
+    pub fn validate(&self, re: &Regex) -> Result<(), String> {
+        for segment in &self.segments {
+            match segment {
+                Segment::Group(index) if *index >= re.captures_len() => {
+                    return Err(format!("pattern has no capture group {}", index));
+                }
+                Segment::Named(name) if !re.capture_names().flatten().any(|n| n == name) => {
+                    return Err(format!("pattern has no capture group named '{}'", name));
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// テンプレートを展開してリネーム後の名前を作成します。
+    ///
+    /// # Arguments
+    ///
+    /// * `m` - ディレクトリ名のマッチ結果
+    /// * `name` - 拡張子を除いた元の名前
+    /// * `ext` - ドットを含む元の拡張子
+    /// * `counter` - 連番
+    pub fn render(&self, m: &DirMatch, name: &OsStr, ext: &OsStr, counter: usize) -> OsString {
+        let mut out = OsString::new();
+        for segment in &self.segments {
+            match segment {
+                Segment::Literal(text) => out.push(text),
+                Segment::Group(index) => out.push(m.groups.get(*index).map_or("", |g| g)),
+                Segment::Named(name) => out.push(m.named.get(name).map_or("", |g| g)),
+                Segment::Prefix => out.push(&m.prefix),
+                Segment::Name => out.push(name),
+                Segment::Ext => out.push(ext),
+                Segment::Counter(width) => {
+                    out.push(format!("{:0width$}", counter, width = *width));
+                }
+            }
+        }
+        out
+    }
+
+    /// 名前が既にこのテンプレートの形式になっているかを判定します。
+    ///
+    /// キャプチャグループは実際の値、元の名前と拡張子は任意のバイト列、連番は数字として照合します。
+    pub fn matches(&self, m: &DirMatch, name: &OsStr) -> bool {
+        let mut pattern = String::from("^");
+        for segment in &self.segments {
+            let fixed = match segment {
+                Segment::Literal(text) => text,
+                Segment::Group(index) => m.groups.get(*index).map_or("", |g| g),
+                Segment::Named(name) => m.named.get(name).map_or("", |g| g),
+                Segment::Prefix => &m.prefix,
+                Segment::Name | Segment::Ext => {
+                    pattern.push_str("(?s-u:.)*");
+                    continue;
+                }
+                Segment::Counter(_) => {
+                    pattern.push_str(r"\d+");
+                    continue;
+                }
+            };
+            pattern.push_str(&regex::escape(fixed));
+        }
+        pattern.push('$');
+        bytes::Regex::new(&pattern).is_ok_and(|re| re.is_match(name.as_encoded_bytes()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(
+        template: &Template,
+        m: &DirMatch,
+        name: &str,
+        ext: &str,
+        counter: usize,
+    ) -> OsString {
+        template.render(m, OsStr::new(name), OsStr::new(ext), counter)
+    }
+
+    #[test]
+    fn test_render_groups() {
+        let re = Regex::new(r"(?<year>\d{4})(\d{2})(\d{2})").unwrap();
+        let m = DirMatch::capture(&re, "20241231_sample");
+        let template = Template::parse("{1}-{2}-{3}_{name}{ext}").unwrap();
+        assert_eq!(
+            render(&template, &m, "test", ".txt", 1),
+            "2024-12-31_test.txt"
+        );
+        let template = Template::parse("{year}_{counter:3}_{prefix}{ext}").unwrap();
+        assert_eq!(
+            render(&template, &m, "test", ".txt", 7),
+            "2024_007_20241231.txt"
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let re = Regex::new(r"(\d+)").unwrap();
+        assert!(Template::parse("{1").is_err());
+        assert!(Template::parse("{}").is_err());
+        assert!(Template::parse("a}b").is_err());
+        assert!(Template::parse("{counter:x}").is_err());
+        assert_eq!(
+            render(
+                &Template::parse("{{{name}}}").unwrap(),
+                &DirMatch::default(),
+                "a",
+                "",
+                0
+            ),
+            "{a}"
+        );
+        assert!(Template::parse("{2}").unwrap().validate(&re).is_err());
+        assert!(Template::parse("{year}").unwrap().validate(&re).is_err());
+    }
+
+    #[test]
+    fn test_matches() {
+        let re = Regex::new(r"(\d{4})(\d{2})(\d{2})").unwrap();
+        let m = DirMatch::capture(&re, "20241231_sample");
+        let template = Template::parse("{1}-{2}-{3}_{name}{ext}").unwrap();
+        assert!(template.matches(&m, OsStr::new("2024-12-31_test.txt")));
+        assert!(!template.matches(&m, OsStr::new("test.txt")));
+    }
+}